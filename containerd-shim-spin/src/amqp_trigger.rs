@@ -0,0 +1,429 @@
+//! This module contains the AMQP trigger, which delivers messages from AMQP 0.9.1 (RabbitMQ) queues to components
+//!
+//! It is configured like the other message triggers, see
+//! [`handle_message`]. The broker is set with `url`, including credentials and
+//! virtual host. Components are bound to queues:
+//!
+//! ```toml
+//! [[trigger.amqp]]
+//! component = "invoices"
+//! queue = "invoices"
+//! prefetch = 10
+//! on_failure = "dead-letter"
+//! ```
+//!
+//! Up to `prefetch` messages are handled concurrently. A message is
+//! acknowledged once the component handled it successfully. Otherwise it is
+//! requeued after a delay, which grows with the number of times the message
+//! was delivered, or, with
+//! `on_failure = "dead-letter"`, rejected so that the broker routes it to the
+//! dead letter exchange of the queue.
+
+use std::{future::Future, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::{future, StreamExt};
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions},
+    types::{AMQPValue, FieldTable},
+    Connection, ConnectionProperties,
+};
+use serde::Deserialize;
+use spin_app::App;
+use spin_factors::RuntimeFactors;
+use spin_trigger::{Trigger, TriggerApp};
+
+use crate::{
+    shutdown::{Shutdown, ShutdownArgs},
+    trigger::{handle_message, REDELIVERY_BACKOFF},
+};
+
+/// Number of unacknowledged messages a trigger receives if it does not set `prefetch`
+const DEFAULT_PREFETCH: u16 = 1;
+
+/// Application-level settings of the AMQP trigger
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AmqpTriggerMetadata {
+    /// URL of the broker, including credentials and virtual host
+    url: Option<String>,
+}
+
+/// What happens to a message the component failed to handle
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum FailurePolicy {
+    /// Put the message back on the queue for redelivery
+    #[default]
+    Requeue,
+    /// Reject the message so that it is routed to the dead letter exchange of the queue
+    DeadLetter,
+}
+
+impl FailurePolicy {
+    fn nack_options(self) -> BasicNackOptions {
+        BasicNackOptions {
+            multiple: false,
+            requeue: self == Self::Requeue,
+        }
+    }
+
+    /// How long to wait before settling a message the component failed to
+    /// handle, given how many times it was delivered before. Requeued messages
+    /// are held back so that a failing message is not redelivered right away.
+    fn delay(self, previous_deliveries: u32) -> Duration {
+        match self {
+            Self::Requeue => REDELIVERY_BACKOFF.delay(previous_deliveries + 1),
+            Self::DeadLetter => Duration::ZERO,
+        }
+    }
+}
+
+/// Configuration of an AMQP trigger in the Spin manifest
+#[derive(Clone, Debug, Deserialize)]
+struct AmqpTriggerConfig {
+    component: String,
+    queue: String,
+    #[serde(default)]
+    prefetch: Option<u16>,
+    #[serde(default)]
+    on_failure: FailurePolicy,
+}
+
+pub(crate) struct AmqpTrigger {
+    url: String,
+    consumers: Vec<(String, AmqpTriggerConfig)>,
+    shutdown: Shutdown,
+}
+
+impl<F: RuntimeFactors> Trigger<F> for AmqpTrigger {
+    const TYPE: &'static str = "amqp";
+    type CliArgs = ShutdownArgs;
+    type InstanceState = ();
+
+    fn new(cli_args: Self::CliArgs, app: &App) -> Result<Self> {
+        let trigger_type = <Self as Trigger<F>>::TYPE;
+        let metadata = app
+            .get_trigger_metadata::<AmqpTriggerMetadata>(trigger_type)?
+            .unwrap_or_default();
+        let url = metadata.url.context(
+            "no AMQP broker configured: set `url` in the [trigger.amqp] table of the runtime config",
+        )?;
+        let consumers = app
+            .trigger_configs::<AmqpTriggerConfig>(trigger_type)?
+            .into_iter()
+            .map(|(id, config)| {
+                anyhow::ensure!(
+                    config.prefetch != Some(0),
+                    "`prefetch` of AMQP trigger {id:?} must be at least 1"
+                );
+                Ok((id.to_string(), config))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            url,
+            consumers,
+            shutdown: cli_args.shutdown,
+        })
+    }
+
+    async fn run(self, trigger_app: TriggerApp<Self, F>) -> Result<()> {
+        let connection = Connection::connect(&self.url, ConnectionProperties::default())
+            .await
+            .context("failed to connect to AMQP broker")?;
+        let trigger_app = &trigger_app;
+        let connection = &connection;
+        let shutdown = &self.shutdown;
+        future::try_join_all(self.consumers.iter().map(|(id, config)| async move {
+            consume(connection, id, config, shutdown, |payload| async move {
+                handle_message(trigger_app, &config.component, &payload)
+                    .await
+                    .with_context(|| format!("AMQP trigger {id:?} failed to handle message"))
+            })
+            .await
+            .with_context(|| format!("AMQP trigger {id:?} failed"))
+        }))
+        .await?;
+        Ok(())
+    }
+}
+
+/// Passes the messages of the queue to the handler until the shutdown starts.
+/// Messages that were received but not handled yet are returned to the queue
+/// by the broker once the channel is closed.
+async fn consume<H, Fut>(
+    connection: &Connection,
+    consumer_tag: &str,
+    config: &AmqpTriggerConfig,
+    shutdown: &Shutdown,
+    handle: H,
+) -> Result<()>
+where
+    H: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    // Each trigger uses its own channel, as the prefetch count applies per channel
+    let channel = connection.create_channel().await?;
+    let prefetch = config.prefetch.unwrap_or(DEFAULT_PREFETCH);
+    channel
+        .basic_qos(prefetch, BasicQosOptions::default())
+        .await?;
+    let consumer = channel
+        .basic_consume(
+            &config.queue,
+            consumer_tag,
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("failed to consume queue {:?}", config.queue))?;
+    log::info!(" >>> consuming AMQP queue {:?}", config.queue);
+    let handle = &handle;
+    consumer
+        .take_until(shutdown.stopped())
+        .for_each_concurrent(usize::from(prefetch), |delivery| async move {
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    log::error!(" >>> failed to receive AMQP message: {e}");
+                    return;
+                }
+            };
+            let handled = handle(delivery.data.clone()).await;
+            let result = match settlement(&delivery, config.on_failure, handled) {
+                Settlement::Ack => delivery.ack(BasicAckOptions::default()).await,
+                Settlement::Nack { delay, options } => {
+                    // The message is settled right away once the shutdown started
+                    shutdown.unless_stopped(tokio::time::sleep(delay)).await;
+                    delivery.nack(options).await
+                }
+            };
+            if let Err(e) = result {
+                log::error!(" >>> failed to acknowledge AMQP message: {e}");
+            }
+        })
+        .await;
+    anyhow::ensure!(
+        shutdown.is_stopping(),
+        "stopped consuming queue {:?}",
+        config.queue
+    );
+    Ok(())
+}
+
+/// How a message is settled once the component handled it
+#[derive(Debug, PartialEq)]
+enum Settlement {
+    /// Acknowledge the message
+    Ack,
+    /// Negatively acknowledge the message with the options after the delay
+    Nack {
+        delay: Duration,
+        options: BasicNackOptions,
+    },
+}
+
+/// Decides how a message is settled once the component handled it, applying
+/// the failure policy of the trigger if the component failed
+fn settlement(delivery: &Delivery, policy: FailurePolicy, handled: Result<()>) -> Settlement {
+    let Err(e) = handled else {
+        return Settlement::Ack;
+    };
+    let delay = policy.delay(previous_deliveries(delivery));
+    log::error!(" >>> applying {policy:?} policy in {delay:?}: {e:?}");
+    Settlement::Nack {
+        delay,
+        options: policy.nack_options(),
+    }
+}
+
+/// Returns how many times the message was delivered before, as counted by the
+/// `x-delivery-count` header of quorum queues. Other queues only tell whether
+/// the message was delivered before.
+fn previous_deliveries(delivery: &Delivery) -> u32 {
+    let count = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get("x-delivery-count"))
+        .and_then(|count| match *count {
+            AMQPValue::LongLongInt(count) => u32::try_from(count).ok(),
+            AMQPValue::LongInt(count) => u32::try_from(count).ok(),
+            AMQPValue::LongUInt(count) => Some(count),
+            _ => None,
+        });
+    count.unwrap_or(u32::from(delivery.redelivered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::collect_calls;
+
+    #[test]
+    fn trigger_config_defaults() {
+        let config: AmqpTriggerConfig = serde_json::from_value(serde_json::json!({
+            "component": "invoices",
+            "queue": "invoices"
+        }))
+        .unwrap();
+        assert_eq!(config.prefetch, None);
+        assert_eq!(config.on_failure, FailurePolicy::Requeue);
+
+        let config: AmqpTriggerConfig = serde_json::from_value(serde_json::json!({
+            "component": "invoices",
+            "queue": "invoices",
+            "prefetch": 10,
+            "on_failure": "dead-letter"
+        }))
+        .unwrap();
+        assert_eq!(config.prefetch, Some(10));
+        assert_eq!(config.on_failure, FailurePolicy::DeadLetter);
+    }
+
+    #[test]
+    fn failure_policy_nack_options() {
+        assert!(FailurePolicy::Requeue.nack_options().requeue);
+        assert!(!FailurePolicy::DeadLetter.nack_options().requeue);
+        assert!(!FailurePolicy::DeadLetter.nack_options().multiple);
+    }
+
+    #[test]
+    fn requeued_messages_are_held_back() {
+        assert_eq!(FailurePolicy::Requeue.delay(0), REDELIVERY_BACKOFF.delay(1));
+        assert!(FailurePolicy::Requeue.delay(3) > FailurePolicy::Requeue.delay(0));
+        assert_eq!(FailurePolicy::DeadLetter.delay(3), Duration::ZERO);
+    }
+
+    fn delivery(redelivered: bool, delivery_count: Option<AMQPValue>) -> Delivery {
+        let mut properties = lapin::BasicProperties::default();
+        if let Some(count) = delivery_count {
+            let mut headers = FieldTable::default();
+            headers.insert("x-delivery-count".into(), count);
+            properties = properties.with_headers(headers);
+        }
+        Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "invoices".into(),
+            redelivered,
+            properties,
+            data: b"invoice".to_vec(),
+            acker: Default::default(),
+        }
+    }
+
+    #[test]
+    fn previous_deliveries_of_delivery() {
+        assert_eq!(previous_deliveries(&delivery(false, None)), 0);
+        assert_eq!(previous_deliveries(&delivery(true, None)), 1);
+        let count = AMQPValue::LongLongInt(4);
+        assert_eq!(previous_deliveries(&delivery(true, Some(count))), 4);
+    }
+
+    #[test]
+    fn settlement_of_handled_messages() {
+        let failed = || Err(anyhow::anyhow!("failed"));
+        let redelivered = delivery(true, Some(AMQPValue::LongLongInt(2)));
+        for policy in [FailurePolicy::Requeue, FailurePolicy::DeadLetter] {
+            assert_eq!(settlement(&redelivered, policy, Ok(())), Settlement::Ack);
+        }
+        assert_eq!(
+            settlement(&redelivered, FailurePolicy::Requeue, failed()),
+            Settlement::Nack {
+                delay: REDELIVERY_BACKOFF.delay(3),
+                options: BasicNackOptions {
+                    multiple: false,
+                    requeue: true
+                },
+            }
+        );
+        assert_eq!(
+            settlement(&redelivered, FailurePolicy::DeadLetter, failed()),
+            Settlement::Nack {
+                delay: Duration::ZERO,
+                options: BasicNackOptions {
+                    multiple: false,
+                    requeue: false
+                },
+            }
+        );
+    }
+
+    /// Connects to the broker given by `AMQP_URL`, such as a local RabbitMQ
+    async fn local_connection() -> Connection {
+        let url = std::env::var("AMQP_URL").unwrap_or_else(|_| "amqp://127.0.0.1:5672".into());
+        Connection::connect(&url, ConnectionProperties::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local AMQP broker"]
+    async fn failed_messages_are_requeued_after_a_delay() {
+        use lapin::{
+            options::{BasicPublishOptions, QueueDeclareOptions},
+            BasicProperties,
+        };
+
+        let connection = local_connection().await;
+        let queue = format!("spin-test-{}", std::process::id());
+        let channel = connection.create_channel().await.unwrap();
+        let declare = QueueDeclareOptions {
+            auto_delete: true,
+            ..Default::default()
+        };
+        channel
+            .queue_declare(&queue, declare, FieldTable::default())
+            .await
+            .unwrap();
+        channel
+            .basic_publish(
+                "",
+                &queue,
+                BasicPublishOptions::default(),
+                b"invoice",
+                BasicProperties::default(),
+            )
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let config = AmqpTriggerConfig {
+            component: "invoices".to_string(),
+            queue,
+            prefetch: None,
+            on_failure: FailurePolicy::Requeue,
+        };
+        let shutdown = Shutdown::default();
+        let deliveries = std::cell::Cell::new(0);
+        // The handler fails the first delivery
+        let calls = collect_calls(2, |tx| {
+            consume(
+                &connection,
+                "spin-test",
+                &config,
+                &shutdown,
+                move |payload| {
+                    tx.send((payload, std::time::Instant::now())).unwrap();
+                    deliveries.set(deliveries.get() + 1);
+                    let first = deliveries.get() == 1;
+                    async move {
+                        anyhow::ensure!(!first, "failed");
+                        Ok(())
+                    }
+                },
+            )
+        })
+        .await;
+        let [(first, failed_at), (second, redelivered_at)] = &calls[..] else {
+            unreachable!();
+        };
+        assert_eq!(first, b"invoice");
+        assert_eq!(second, b"invoice");
+        let redelivered_after = *redelivered_at - *failed_at;
+        assert!(redelivered_after >= FailurePolicy::Requeue.delay(0));
+    }
+}
@@ -0,0 +1,291 @@
+//! This module contains the configuration of the app run by the shim
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+#[cfg(feature = "http")]
+use crate::http_trigger::HttpConfig;
+use crate::{
+    constants,
+    exit_policy::ExitPolicy,
+    source::ImageReference,
+    utils::{
+        env_list, is_env_flag_set, shutdown_drain_period, telemetry_resource_attributes,
+        OTEL_RESOURCE_ATTRIBUTES_ENV,
+    },
+    variables::VariablesConfig,
+};
+
+/// Configuration of the app, read from the environment of the container once.
+///
+/// It is passed to the component loader, the cache, the variables provider and
+/// the triggers rather than exported to the environment of the process, which
+/// is shared by all threads of the shim. Invalid settings fail the app before
+/// it is loaded.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AppConfig {
+    /// Directory the shim writes the locked app and the files of OCI
+    /// applications to
+    pub(crate) scratch_dir: PathBuf,
+    /// Directory of the cache holding the components and files of the app
+    pub(crate) cache_dir: PathBuf,
+    /// State directory of the triggers, used for key value stores and SQLite
+    /// databases
+    pub(crate) state_dir: PathBuf,
+    /// Where the values of the application variables come from
+    pub(crate) variables: VariablesConfig,
+    /// The runtime config file the triggers are configured with, once resolved
+    pub(crate) runtime_config_file: Option<PathBuf>,
+    /// OpenTelemetry resource attributes of the app, in the format of
+    /// `OTEL_RESOURCE_ATTRIBUTES`
+    pub(crate) telemetry_resource_attributes: Option<String>,
+    /// Unique name of the container, which identifies the app
+    pub(crate) app_id: String,
+    /// Components to run, if not all of them
+    pub(crate) components_to_retain: Option<Vec<String>>,
+    /// Components not to run
+    pub(crate) components_to_exclude: Option<Vec<String>>,
+    /// Trigger types to run, if not all of them
+    pub(crate) triggers_to_run: Option<Vec<String>>,
+    /// Whether the triggers the shim cannot run are left out rather than
+    /// failing the app
+    pub(crate) skip_unsupported_triggers: bool,
+    /// The runtime config layers, if listed rather than read from the default
+    /// location
+    pub(crate) runtime_config_paths: Option<Vec<PathBuf>>,
+    /// Whether environment variable references in the runtime config are
+    /// expanded
+    pub(crate) expand_runtime_config: bool,
+    /// What happens when a trigger exits
+    pub(crate) exit_policy: ExitPolicy,
+    /// How long in-flight work may take to finish after a termination signal
+    pub(crate) drain_period: Duration,
+    /// Settings of the HTTP trigger
+    #[cfg(feature = "http")]
+    pub(crate) http: HttpConfig,
+}
+
+impl AppConfig {
+    pub(crate) fn from_env() -> Result<Self> {
+        let scratch_dir = env_dir(constants::SPIN_SCRATCH_DIR_ENV)
+            .unwrap_or_else(|| constants::SPIN_SCRATCH_DIR_DEFAULT.into());
+        Ok(Self {
+            cache_dir: env_dir(constants::SPIN_CACHE_DIR_ENV)
+                .unwrap_or_else(|| scratch_dir.join(constants::SPIN_DEFAULT_CACHE_DIR)),
+            state_dir: env_dir(constants::SPIN_STATE_DIR_ENV)
+                .unwrap_or_else(|| scratch_dir.join(constants::SPIN_DEFAULT_STATE_DIR)),
+            scratch_dir,
+            variables: VariablesConfig::from_env()?,
+            runtime_config_file: None,
+            telemetry_resource_attributes: env::var(OTEL_RESOURCE_ATTRIBUTES_ENV).ok(),
+            // The `HOSTNAME` environment variable should contain the fully unique container name
+            app_id: env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into()),
+            components_to_retain: env_list(constants::SPIN_COMPONENTS_TO_RETAIN_ENV),
+            components_to_exclude: env_list(constants::SPIN_COMPONENTS_TO_EXCLUDE_ENV),
+            triggers_to_run: env_list(constants::SPIN_TRIGGERS_TO_RUN_ENV),
+            skip_unsupported_triggers: is_env_flag_set(
+                constants::SPIN_SKIP_UNSUPPORTED_TRIGGERS_ENV,
+            ),
+            // An empty list configures no runtime config, rather than the default one
+            runtime_config_paths: env::var(constants::SPIN_RUNTIME_CONFIG_PATHS_ENV).ok().map(
+                |paths| {
+                    paths
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|path| !path.is_empty())
+                        .map(PathBuf::from)
+                        .collect()
+                },
+            ),
+            expand_runtime_config: is_env_flag_set(constants::SPIN_RUNTIME_CONFIG_EXPAND_ENV),
+            exit_policy: ExitPolicy::from_env()?,
+            drain_period: shutdown_drain_period()?,
+            #[cfg(feature = "http")]
+            http: HttpConfig::from_env()?,
+        })
+    }
+
+    /// Adds the image of an OCI application to the OpenTelemetry resource
+    /// attributes. Attributes that are already set by the container take
+    /// precedence.
+    pub(crate) fn add_image_resource_attributes(&mut self, image: &ImageReference) {
+        let existing = self.telemetry_resource_attributes.as_deref();
+        self.telemetry_resource_attributes = Some(telemetry_resource_attributes(image, existing));
+    }
+
+    /// Path of the locked app of OCI applications
+    pub(crate) fn locked_app_path(&self) -> PathBuf {
+        self.scratch_dir.join(constants::SPIN_OCI_LOCKED_APP_FILE)
+    }
+
+    /// Creates the directories the shim writes to, failing with the
+    /// environment variable to set when one of them is not writable, such as
+    /// when the root filesystem of the container is read-only.
+    pub(crate) fn ensure_writable(&self) -> Result<()> {
+        for (dir, env_name) in [
+            (&self.scratch_dir, constants::SPIN_SCRATCH_DIR_ENV),
+            (&self.cache_dir, constants::SPIN_CACHE_DIR_ENV),
+            (&self.state_dir, constants::SPIN_STATE_DIR_ENV),
+        ] {
+            check_writable(dir).with_context(|| {
+                format!(
+                    "directory {dir:?} is not writable; if the root filesystem of the container is read-only, mount a writable volume such as an emptyDir and set {} (or {env_name}) to a directory in it",
+                    constants::SPIN_SCRATCH_DIR_ENV
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the directory named by the environment variable, if it is set and
+/// not empty
+fn env_dir(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Creates the directory and checks that files can be created in it
+fn check_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).context("failed to create directory")?;
+    let probe = dir.join(format!(".spin-write-check-{}", std::process::id()));
+    fs::write(&probe, b"").context("failed to create file")?;
+    fs::remove_file(&probe).context("failed to remove file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writable_locations_default_to_scratch_dir() {
+        temp_env::with_vars(
+            [
+                (constants::SPIN_SCRATCH_DIR_ENV, Some("/scratch")),
+                (constants::SPIN_CACHE_DIR_ENV, None),
+                (constants::SPIN_STATE_DIR_ENV, Some("/data/state")),
+            ],
+            || {
+                let config = AppConfig::from_env().unwrap();
+                assert_eq!(config.locked_app_path(), Path::new("/scratch/spin.json"));
+                assert_eq!(config.cache_dir, Path::new("/scratch/.cache"));
+                assert_eq!(config.state_dir, Path::new("/data/state"));
+            },
+        );
+        temp_env::with_vars_unset(
+            [
+                constants::SPIN_SCRATCH_DIR_ENV,
+                constants::SPIN_CACHE_DIR_ENV,
+                constants::SPIN_STATE_DIR_ENV,
+            ],
+            || {
+                let config = AppConfig::from_env().unwrap();
+                assert_eq!(config.locked_app_path(), Path::new("/spin.json"));
+                assert_eq!(config.cache_dir, Path::new("/.cache"));
+                assert_eq!(config.state_dir, Path::new("/.spin"));
+            },
+        );
+    }
+
+    #[test]
+    fn settings_are_read_from_env() {
+        temp_env::with_vars(
+            [
+                (constants::SPIN_COMPONENTS_TO_RETAIN_ENV, Some("api, web")),
+                (constants::SPIN_COMPONENTS_TO_EXCLUDE_ENV, None),
+                (constants::SPIN_TRIGGERS_TO_RUN_ENV, Some("http")),
+                (constants::SPIN_SKIP_UNSUPPORTED_TRIGGERS_ENV, Some("true")),
+                (
+                    constants::SPIN_RUNTIME_CONFIG_PATHS_ENV,
+                    Some("/base.toml, ,/overrides.toml"),
+                ),
+                (constants::SPIN_TRIGGER_EXIT_POLICY_ENV, Some("wait-all")),
+                (constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV, Some("15")),
+                ("HOSTNAME", Some("app-1")),
+            ],
+            || {
+                let config = AppConfig::from_env().unwrap();
+                assert_eq!(config.app_id, "app-1");
+                assert_eq!(
+                    config.components_to_retain,
+                    Some(vec!["api".to_string(), "web".to_string()])
+                );
+                assert_eq!(config.components_to_exclude, None);
+                assert_eq!(config.triggers_to_run, Some(vec!["http".to_string()]));
+                assert!(config.skip_unsupported_triggers);
+                assert_eq!(
+                    config.runtime_config_paths,
+                    Some(vec![
+                        PathBuf::from("/base.toml"),
+                        PathBuf::from("/overrides.toml")
+                    ])
+                );
+                assert_eq!(config.exit_policy, ExitPolicy::WaitAll);
+                assert_eq!(config.drain_period, Duration::from_secs(15));
+            },
+        );
+
+        // Invalid settings fail before the app is loaded
+        temp_env::with_var(
+            constants::SPIN_TRIGGER_EXIT_POLICY_ENV,
+            Some("sometimes"),
+            || assert!(AppConfig::from_env().is_err()),
+        );
+    }
+
+    #[test]
+    fn image_resource_attributes_are_added() {
+        let image = ImageReference {
+            name: "ghcr.io/spinkube/app:v1".to_string(),
+            digest: None,
+        };
+        let mut config = AppConfig {
+            telemetry_resource_attributes: Some("team=platform".to_string()),
+            ..Default::default()
+        };
+        config.add_image_resource_attributes(&image);
+        assert_eq!(
+            config.telemetry_resource_attributes.as_deref(),
+            Some("container.image.name=ghcr.io/spinkube/app:v1,team=platform")
+        );
+        let mut config = AppConfig::default();
+        config.add_image_resource_attributes(&image);
+        assert_eq!(
+            config.telemetry_resource_attributes.as_deref(),
+            Some("container.image.name=ghcr.io/spinkube/app:v1")
+        );
+    }
+
+    #[test]
+    fn ensure_writable_creates_directories() {
+        let scratch = tempfile::tempdir().unwrap();
+        let config = AppConfig {
+            scratch_dir: scratch.path().into(),
+            cache_dir: scratch.path().join(".cache"),
+            state_dir: scratch.path().join(".spin"),
+            ..Default::default()
+        };
+        config.ensure_writable().unwrap();
+        assert!(config.cache_dir.is_dir());
+        assert!(config.state_dir.is_dir());
+        assert_eq!(fs::read_dir(&config.state_dir).unwrap().count(), 0);
+
+        // A directory cannot be created below a file, whatever the permissions
+        let file = scratch.path().join("file");
+        fs::write(&file, "").unwrap();
+        let config = AppConfig {
+            state_dir: file.join(".spin"),
+            ..config
+        };
+        let err = config.ensure_writable().unwrap_err().to_string();
+        assert!(err.contains(constants::SPIN_SCRATCH_DIR_ENV), "{err}");
+        assert!(err.contains(constants::SPIN_STATE_DIR_ENV), "{err}");
+    }
+}
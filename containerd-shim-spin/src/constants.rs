@@ -1,25 +1,158 @@
+use std::time::Duration;
+
 /// SPIN_ADDR_DEFAULT is the default address and port that the Spin HTTP trigger
 /// listens on.
+#[cfg(feature = "http")]
 pub(crate) const SPIN_ADDR_DEFAULT: &str = "0.0.0.0:80";
 /// SPIN_HTTP_LISTEN_ADDR_ENV is the environment variable that can be used to
 /// override the default address and port that the Spin HTTP trigger listens on.
+#[cfg(feature = "http")]
 pub(crate) const SPIN_HTTP_LISTEN_ADDR_ENV: &str = "SPIN_HTTP_LISTEN_ADDR";
+/// SPIN_HTTP_TLS_CERT_ENV is the environment variable that can be used to
+/// point the Spin HTTP trigger at a PEM encoded certificate chain, for example
+/// one mounted from a Kubernetes TLS secret. Requires SPIN_HTTP_TLS_KEY_ENV.
+#[cfg(feature = "http")]
+pub(crate) const SPIN_HTTP_TLS_CERT_ENV: &str = "SPIN_HTTP_TLS_CERT";
+/// SPIN_HTTP_TLS_KEY_ENV is the environment variable that can be used to
+/// point the Spin HTTP trigger at the PEM encoded private key of the
+/// certificate. Requires SPIN_HTTP_TLS_CERT_ENV.
+#[cfg(feature = "http")]
+pub(crate) const SPIN_HTTP_TLS_KEY_ENV: &str = "SPIN_HTTP_TLS_KEY";
+/// How often the TLS certificate and key are checked for changes. New
+/// connections are served the changed ones.
+#[cfg(feature = "http")]
+pub(crate) const SPIN_HTTP_TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
 /// RUNTIME_CONFIG_PATH specifies the expected location and name of the runtime
 /// config for a Spin application. The runtime config should be loaded into the
 /// root `/` of the container.
 pub(crate) const RUNTIME_CONFIG_PATH: &str = "/runtime-config.toml";
+/// Environment variable that lists the runtime config files of a Spin
+/// application, separated by commas. The files are layered in order, with
+/// later files overriding earlier ones. Overrides [`RUNTIME_CONFIG_PATH`].
+pub(crate) const SPIN_RUNTIME_CONFIG_PATHS_ENV: &str = "SPIN_RUNTIME_CONFIG_PATHS";
+/// Environment variable that enables the expansion of `${VAR}` references to
+/// container environment variables in the runtime config of a Spin application
+pub(crate) const SPIN_RUNTIME_CONFIG_EXPAND_ENV: &str = "SPIN_RUNTIME_CONFIG_EXPAND_ENV";
 /// Describes an OCI layer with Wasm content
 pub(crate) const OCI_LAYER_MEDIA_TYPE_WASM: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// Annotation of the container spec in which CRI runtimes such as containerd
+/// name the image of the container.
+pub(crate) const CRI_IMAGE_NAME_ANNOTATION: &str = "io.kubernetes.cri.image-name";
+/// Environment variable of the container that names the image the Spin
+/// application was distributed with, e.g. `ghcr.io/org/app:v1`, overriding
+/// [`CRI_IMAGE_NAME_ANNOTATION`]. The image reference may be pinned to a
+/// digest, e.g. `ghcr.io/org/app:v1@sha256:...`. The image is recorded in the
+/// locked app metadata and as OpenTelemetry resource attributes.
+pub(crate) const SPIN_OCI_IMAGE_REFERENCE_ENV: &str = "SPIN_OCI_IMAGE_REFERENCE";
+/// Environment variable of the container that provides the digest of the
+/// image manifest when it is not part of SPIN_OCI_IMAGE_REFERENCE_ENV.
+pub(crate) const SPIN_OCI_IMAGE_DIGEST_ENV: &str = "SPIN_OCI_IMAGE_DIGEST";
+/// Placeholder reference used to load OCI applications when the image
+/// reference is not known. It is never reported as the origin of the app.
+pub(crate) const SPIN_OCI_IMAGE_REFERENCE_UNKNOWN: &str = "docker.io/library/unknown:latest";
 /// Expected location of the Spin manifest when loading from a file rather than
 /// an OCI image
 pub(crate) const SPIN_MANIFEST_FILE_PATH: &str = "/spin.toml";
 /// Known prefix for the Spin application variables environment variable
 /// provider: https://github.com/fermyon/spin/blob/436ad589237c02f7aa4693e984132808fd80b863/crates/variables/src/provider/env.rs#L9
 pub(crate) const SPIN_APPLICATION_VARIABLE_PREFIX: &str = "SPIN_VARIABLE";
+/// Environment variable of the container that names a directory holding one
+/// file per Spin application variable, such as a mounted Kubernetes Secret.
+/// Variables read from the directory take precedence over environment variables.
+pub(crate) const SPIN_VARIABLES_SECRETS_DIR_ENV: &str = "SPIN_VARIABLES_SECRETS_DIR";
+/// Environment variable of the container that maps Spin application variables
+/// to the container environment variables providing them, as comma separated
+/// `variable=ENV_NAME` entries, such as `db_url=DATABASE_URL`. The name does not
+/// start with `SPIN_VARIABLE_`, so that Spin does not read it as a variable.
+pub(crate) const SPIN_VARIABLES_MAP_ENV: &str = "SPIN_VARIABLES_MAP";
+/// Environment variable of the container that sets the prefix of the container
+/// environment variables providing unmapped Spin application variables. With
+/// `APP_`, the variable `db_url` is provided by `APP_DB_URL` rather than `DB_URL`.
+pub(crate) const SPIN_VARIABLES_ENV_PREFIX_ENV: &str = "SPIN_VARIABLES_ENV_PREFIX";
+/// Environment variable of the container that names the directory the shim
+/// writes to, such as an emptyDir volume when the root filesystem of the
+/// container is read-only. The locked app, the cache and the state directory are
+/// placed in it unless configured otherwise.
+pub(crate) const SPIN_SCRATCH_DIR_ENV: &str = "SPIN_SCRATCH_DIR";
+/// Default scratch directory, the root of the container
+pub(crate) const SPIN_SCRATCH_DIR_DEFAULT: &str = "/";
+/// Environment variable of the container that overrides the directory of the
+/// cache holding the components and files of Spin applications
+pub(crate) const SPIN_CACHE_DIR_ENV: &str = "SPIN_CACHE_DIR";
+/// Directory of the cache within the scratch directory
+pub(crate) const SPIN_DEFAULT_CACHE_DIR: &str = ".cache";
+/// Environment variable of the container that overrides the state directory of
+/// the triggers
+pub(crate) const SPIN_STATE_DIR_ENV: &str = "SPIN_STATE_DIR";
 /// Working directory for Spin applications
 pub(crate) const SPIN_TRIGGER_WORKING_DIR: &str = "/";
-/// Defines the subset of application components that should be executable by the shim
+/// Defines the subset of application components that should be executable by the shim,
+/// as comma separated component IDs or glob patterns such as `api-*`
 /// If empty or DNE, all components will be supported
 pub(crate) const SPIN_COMPONENTS_TO_RETAIN_ENV: &str = "SPIN_COMPONENTS_TO_RETAIN";
-/// The default state directory for the triggers.
+/// Defines the application components, as comma separated component IDs or glob
+/// patterns, that should not be executable by the shim even if they are retained
+pub(crate) const SPIN_COMPONENTS_TO_EXCLUDE_ENV: &str = "SPIN_COMPONENTS_TO_EXCLUDE";
+/// Defines the subset of trigger types, separated by commas, that should be run by the shim.
+/// Components not bound to any of these triggers are dropped.
+/// If empty or DNE, all trigger types will be run
+pub(crate) const SPIN_TRIGGERS_TO_RUN_ENV: &str = "SPIN_TRIGGERS_TO_RUN";
+/// Environment variable that, when set to a truthy value, drops the triggers
+/// the shim does not support, along with their components, instead of failing
+/// to run the application
+pub(crate) const SPIN_SKIP_UNSUPPORTED_TRIGGERS_ENV: &str = "SPIN_SKIP_UNSUPPORTED_TRIGGERS";
+/// Environment variable of the shim that lists the directories of the node,
+/// separated by colons, searched for executables named `trigger-<type>`. These
+/// Spin trigger plugins run the trigger types that are not built into the shim.
+/// Containers run them from the same paths, so the directories must be mounted
+/// into the containers.
+pub(crate) const SPIN_TRIGGER_PLUGIN_PATH_ENV: &str = "SPIN_TRIGGER_PLUGIN_PATH";
+/// Environment variable of the shim that opts in to running trigger plugins
+/// found in the image of the container. By default, only the plugins installed
+/// on the node are run.
+pub(crate) const SPIN_TRUST_IMAGE_TRIGGER_PLUGINS_ENV: &str = "SPIN_TRUST_IMAGE_TRIGGER_PLUGINS";
+/// The default state directory for the triggers, within the scratch directory.
 pub(crate) const SPIN_DEFAULT_STATE_DIR: &str = ".spin";
+/// Name of the locked app of OCI applications, within the scratch directory
+pub(crate) const SPIN_OCI_LOCKED_APP_FILE: &str = "spin.json";
+/// Environment variable of the shim that can be used to override the location
+/// of the node-local key used to seal components precompiled by the shim.
+pub(crate) const SPIN_PRECOMPILE_KEY_PATH_ENV: &str = "SPIN_PRECOMPILE_KEY_PATH";
+/// Default location of the node-local key used to seal precompiled components.
+/// The key is created on first use if it does not exist.
+pub(crate) const SPIN_PRECOMPILE_KEY_PATH_DEFAULT: &str =
+    "/var/lib/containerd-shim-spin/precompile.key";
+/// Environment variable of the shim that opts in to running precompiled
+/// (native) code shipped in image layers. By default, such layers are rejected
+/// and only components precompiled by the shim itself are loaded.
+pub(crate) const SPIN_TRUST_PRECOMPILED_LAYERS_ENV: &str = "SPIN_TRUST_PRECOMPILED_LAYERS";
+/// Environment variable of the container that sets how many seconds in-flight
+/// work may take to finish after a termination signal. During the drain period,
+/// triggers no longer accept new work. A second signal or the end of the drain
+/// period aborts remaining work. Defaults to 0, which aborts immediately.
+pub(crate) const SPIN_SHUTDOWN_DRAIN_PERIOD_ENV: &str = "SPIN_SHUTDOWN_DRAIN_PERIOD";
+/// Environment variable of the container that sets what happens when a
+/// trigger exits: `first-exit` (the default) stops the app when any trigger
+/// exits, `wait-all` waits for all triggers to exit, and `restart` restarts
+/// triggers that failed, with exponential backoff, except the command trigger.
+pub(crate) const SPIN_TRIGGER_EXIT_POLICY_ENV: &str = "SPIN_TRIGGER_EXIT_POLICY";
+/// Delay before a failed trigger is restarted for the first time. The delay
+/// doubles with each consecutive failure.
+pub(crate) const SPIN_TRIGGER_RESTART_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// Longest delay before a failed trigger is restarted. A trigger that ran for
+/// at least this long before failing is restarted after the initial delay.
+pub(crate) const SPIN_TRIGGER_RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Delay before a message a component failed to handle is redelivered for the
+/// first time. The delay doubles with each consecutive failure.
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+pub(crate) const SPIN_MESSAGE_REDELIVERY_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// Longest delay before a failed message is redelivered. It is well below the
+/// default `max.poll.interval.ms` of Kafka consumers, so that consumers waiting
+/// to redeliver a message are not removed from their group.
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+pub(crate) const SPIN_MESSAGE_REDELIVERY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// Exit code of the container when a command trigger component traps. It is
+/// distinct from the code used when the shim fails to run the app (137), so
+/// that `Job` failure policies can tell the two apart.
+#[cfg(feature = "command")]
+pub(crate) const SPIN_COMMAND_TRAP_EXIT_CODE: i32 = 134;
@@ -0,0 +1,337 @@
+//! This module contains the cron trigger, which runs components on the schedules given in the Spin manifest
+//!
+//! Components are bound to the trigger in the manifest:
+//!
+//! ```toml
+//! [[trigger.cron]]
+//! component = "cleanup"
+//! cron_expression = "0 */5 * * * *"
+//! timezone = "Europe/Berlin"
+//! ```
+//!
+//! The component must export the `handle-cron-event` function of the
+//! `fermyon:spin-cron` world. Runs of the same trigger never overlap: ticks
+//! that pass while a run is still in progress are skipped. On shutdown, runs in
+//! progress finish, but no further runs are started.
+
+use std::{future::Future, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures::future;
+use serde::Deserialize;
+use spin_app::App;
+use spin_factors::RuntimeFactors;
+use spin_trigger::{Trigger, TriggerApp};
+use wasmtime::component::Val;
+
+use crate::shutdown::{Shutdown, ShutdownArgs};
+
+/// Name of the function exported by cron components
+const HANDLE_CRON_EVENT_EXPORT: &str = "handle-cron-event";
+
+/// Configuration of a cron trigger in the Spin manifest
+#[derive(Clone, Debug, Deserialize)]
+struct CronTriggerConfig {
+    component: String,
+    cron_expression: String,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+/// A cron expression evaluated in a timezone
+#[derive(Clone, Debug)]
+pub(crate) struct CronSchedule {
+    schedule: cron::Schedule,
+    timezone: Tz,
+}
+
+impl CronSchedule {
+    /// Parses a cron expression with a seconds field, evaluated in the given
+    /// IANA timezone or UTC.
+    pub(crate) fn parse(expression: &str, timezone: Option<&str>) -> Result<Self> {
+        let schedule = cron::Schedule::from_str(expression)
+            .with_context(|| format!("invalid cron expression {expression:?}"))?;
+        let timezone = match timezone {
+            Some(timezone) => timezone
+                .parse::<Tz>()
+                .map_err(|e| anyhow!("invalid timezone {timezone:?}: {e}"))?,
+            None => Tz::UTC,
+        };
+        Ok(Self { schedule, timezone })
+    }
+
+    /// Returns the first tick strictly after the given time
+    fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule
+            .after(&time.with_timezone(&self.timezone))
+            .next()
+            .map(|tick| tick.with_timezone(&Utc))
+    }
+}
+
+/// Source of the current time for the cron scheduler
+pub(crate) trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> impl Future<Output = ()> + Send;
+}
+
+/// The wall clock of the container
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> impl Future<Output = ()> + Send {
+        let duration = (deadline - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(duration)
+    }
+}
+
+/// Runs the job at every tick of the schedule, waiting for each run to finish
+/// before scheduling the next one, until the shutdown starts.
+pub(crate) async fn run_schedule<C, J, Fut>(
+    schedule: &CronSchedule,
+    clock: &C,
+    shutdown: &Shutdown,
+    mut job: J,
+) where
+    C: Clock,
+    J: FnMut(DateTime<Utc>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut now = clock.now();
+    while let Some(tick) = schedule.next_after(now) {
+        if shutdown
+            .unless_stopped(clock.sleep_until(tick))
+            .await
+            .is_none()
+        {
+            return;
+        }
+        job(tick).await;
+        // Never schedule the same tick twice, even if the clock went backwards
+        now = clock.now().max(tick);
+        if schedule
+            .next_after(tick)
+            .is_some_and(|missed| missed <= now)
+        {
+            log::warn!(
+                " >>> cron run scheduled at {tick} finished at {now}, skipping the ticks missed in between"
+            );
+        }
+    }
+}
+
+pub(crate) struct CronTrigger {
+    schedules: Vec<(String, CronTriggerConfig, CronSchedule)>,
+    shutdown: Shutdown,
+}
+
+impl<F: RuntimeFactors> Trigger<F> for CronTrigger {
+    const TYPE: &'static str = "cron";
+    type CliArgs = ShutdownArgs;
+    type InstanceState = ();
+
+    fn new(cli_args: Self::CliArgs, app: &App) -> Result<Self> {
+        let schedules = app
+            .trigger_configs::<CronTriggerConfig>(<Self as Trigger<F>>::TYPE)?
+            .into_iter()
+            .map(|(id, config)| {
+                let schedule =
+                    CronSchedule::parse(&config.cron_expression, config.timezone.as_deref())
+                        .with_context(|| format!("invalid configuration of cron trigger {id:?}"))?;
+                Ok((id.to_string(), config, schedule))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            schedules,
+            shutdown: cli_args.shutdown,
+        })
+    }
+
+    async fn run(self, trigger_app: TriggerApp<Self, F>) -> Result<()> {
+        let trigger_app = &trigger_app;
+        future::join_all(self.schedules.iter().map(|(id, config, schedule)| {
+            run_schedule(
+                schedule,
+                &SystemClock,
+                &self.shutdown,
+                move |tick| async move {
+                    log::info!(" >>> running cron trigger {id:?} scheduled at {tick}");
+                    if let Err(e) = handle_cron_event(trigger_app, &config.component, tick).await {
+                        log::error!(" >>> cron trigger {id:?} failed: {e:?}");
+                    }
+                },
+            )
+        }))
+        .await;
+        Ok(())
+    }
+}
+
+/// Invokes the `handle-cron-event` export of the component
+async fn handle_cron_event<F: RuntimeFactors>(
+    trigger_app: &TriggerApp<CronTrigger, F>,
+    component_id: &str,
+    tick: DateTime<Utc>,
+) -> Result<()> {
+    let (instance, mut store) = trigger_app.prepare(component_id)?.instantiate(()).await?;
+    let func = instance
+        .get_func(&mut store, HANDLE_CRON_EVENT_EXPORT)
+        .with_context(|| {
+            format!("component {component_id:?} does not export {HANDLE_CRON_EVENT_EXPORT:?}")
+        })?;
+    let metadata = Val::Record(vec![(
+        "timestamp".to_string(),
+        Val::U64(tick.timestamp().try_into().unwrap_or_default()),
+    )]);
+    let mut results = [Val::Bool(false)];
+    func.call_async(&mut store, &[metadata], &mut results)
+        .await?;
+    func.post_return_async(&mut store).await?;
+    match &results[0] {
+        Val::Result(Ok(_)) => Ok(()),
+        Val::Result(Err(e)) => bail!("{HANDLE_CRON_EVENT_EXPORT} returned an error: {e:?}"),
+        other => bail!("unexpected result of {HANDLE_CRON_EVENT_EXPORT}: {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::trigger::collect_calls;
+
+    /// A clock that only moves when the scheduler sleeps or a run takes time
+    struct FakeClock {
+        now: Mutex<DateTime<Utc>>,
+    }
+
+    impl FakeClock {
+        fn new(now: DateTime<Utc>) -> Self {
+            Self {
+                now: Mutex::new(now),
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep_until(&self, deadline: DateTime<Utc>) -> impl Future<Output = ()> + Send {
+            let mut now = self.now.lock().unwrap();
+            *now = (*now).max(deadline);
+            tokio::task::yield_now()
+        }
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    /// Returns the ticks of the first `count` runs, where each run takes `run_time`
+    async fn collect_runs(
+        schedule: &CronSchedule,
+        clock: &FakeClock,
+        count: usize,
+        run_time: Duration,
+    ) -> Vec<DateTime<Utc>> {
+        let shutdown = Shutdown::default();
+        let runs = collect_calls(count, |tx| {
+            run_schedule(schedule, clock, &shutdown, move |tick| {
+                tx.send((tick, clock.now())).unwrap();
+                clock.advance(run_time);
+                async {}
+            })
+        })
+        .await;
+        runs.into_iter()
+            .map(|(tick, started)| {
+                assert_eq!(tick, started, "run did not start on its tick");
+                tick
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_on_schedule() {
+        let schedule = CronSchedule::parse("0 */5 * * * *", None).unwrap();
+        let clock = FakeClock::new(utc(2024, 1, 1, 0, 1, 0));
+        let runs = collect_runs(&schedule, &clock, 3, Duration::seconds(1)).await;
+        assert_eq!(
+            runs,
+            [
+                utc(2024, 1, 1, 0, 5, 0),
+                utc(2024, 1, 1, 0, 10, 0),
+                utc(2024, 1, 1, 0, 15, 0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_do_not_overlap() {
+        let schedule = CronSchedule::parse("0 * * * * *", None).unwrap();
+        let clock = FakeClock::new(utc(2024, 1, 1, 0, 0, 30));
+        // Each run takes two and a half minutes, so two ticks are missed after every run
+        let runs = collect_runs(&schedule, &clock, 3, Duration::seconds(150)).await;
+        assert_eq!(
+            runs,
+            [
+                utc(2024, 1, 1, 0, 1, 0),
+                utc(2024, 1, 1, 0, 4, 0),
+                utc(2024, 1, 1, 0, 7, 0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_in_timezone() {
+        let schedule = CronSchedule::parse("0 0 9 * * *", Some("America/New_York")).unwrap();
+        let clock = FakeClock::new(utc(2024, 3, 9, 0, 0, 0));
+        let runs = collect_runs(&schedule, &clock, 2, Duration::seconds(1)).await;
+        // Daylight saving time starts on March 10th
+        assert_eq!(
+            runs,
+            [utc(2024, 3, 9, 14, 0, 0), utc(2024, 3, 10, 13, 0, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_the_run_in_progress_finish() {
+        let schedule = CronSchedule::parse("0 * * * * *", None).unwrap();
+        let clock = FakeClock::new(utc(2024, 1, 1, 0, 0, 30));
+        let shutdown = Shutdown::default();
+        let runs = Mutex::new(Vec::new());
+        run_schedule(&schedule, &clock, &shutdown, |tick| {
+            // The shutdown starts while the first run is in progress
+            shutdown.stop();
+            let runs = &runs;
+            async move {
+                tokio::task::yield_now().await;
+                runs.lock().unwrap().push(tick);
+            }
+        })
+        .await;
+        assert_eq!(*runs.lock().unwrap(), [utc(2024, 1, 1, 0, 1, 0)]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_schedules() {
+        assert!(CronSchedule::parse("not a schedule", None).is_err());
+        assert!(CronSchedule::parse("0 * * * * *", Some("Mars/Olympus_Mons")).is_err());
+        assert!(CronSchedule::parse("0 * * * * *", Some("Europe/Berlin")).is_ok());
+    }
+}
@@ -1,46 +1,75 @@
 use std::{
     collections::{hash_map::DefaultHasher, HashSet},
     env,
+    future::Future,
     hash::{Hash, Hasher},
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use containerd_shim_wasm::{
     container::{Engine, RuntimeContext, Stdio},
     sandbox::WasmLayer,
     version,
 };
-use futures::future;
+use futures::future::{self, Either, LocalBoxFuture};
 use log::info;
 use spin_app::locked::LockedApp;
-use spin_trigger::cli::NoCliArgs;
-use spin_trigger_http::HttpTrigger;
-use spin_trigger_redis::RedisTrigger;
-use tokio::runtime::Runtime;
-use trigger_command::CommandTrigger;
-use trigger_mqtt::MqttTrigger;
-use trigger_sqs::SqsTrigger;
+use tokio::{runtime::Runtime, sync::mpsc};
 
 use crate::{
-    constants,
-    source::Source,
-    trigger::{
-        self, get_supported_triggers, COMMAND_TRIGGER_TYPE, HTTP_TRIGGER_TYPE, MQTT_TRIGGER_TYPE,
-        REDIS_TRIGGER_TYPE, SQS_TRIGGER_TYPE,
-    },
-    utils::{
-        configure_application_variables_from_environment_variables, initialize_cache,
-        is_wasm_content, parse_addr,
-    },
+    config::AppConfig,
+    constants, exit_policy,
+    plugin::TriggerPlugins,
+    precompile::PrecompileKey,
+    registry::{TriggerContext, TriggerRegistry},
+    runtime_config::resolve_runtime_config,
+    shutdown::Shutdown,
+    source::{ImageReference, Source},
+    telemetry,
+    trigger::{self, TriggerFuture},
+    utils::{initialize_cache, is_env_flag_set, is_wasm_content},
+    variables::check_required_variables,
 };
 
 #[derive(Clone)]
 pub struct SpinEngine {
     pub(crate) wasmtime_engine: wasmtime::Engine,
+    /// Key used to seal components precompiled by the shim. Precompilation is
+    /// disabled if no key is available.
+    pub(crate) precompile_key: Option<PrecompileKey>,
+    /// Whether precompiled layers shipped in images may be loaded
+    pub(crate) trust_precompiled_layers: bool,
+    /// Image of the container, as annotated in its spec
+    pub(crate) image: Option<ImageReference>,
+    /// Trigger plugins installed on the node
+    pub(crate) trigger_plugins: TriggerPlugins,
 }
 
 impl Default for SpinEngine {
     fn default() -> Self {
+        // The engine is created by the shim process, so these settings are
+        // controlled by the node rather than by the container spec.
+        let precompile_key = PrecompileKey::from_env()
+            .map_err(|e| log::warn!("precompilation is disabled: {e:?}"))
+            .ok();
+        let trust_precompiled_layers =
+            is_env_flag_set(constants::SPIN_TRUST_PRECOMPILED_LAYERS_ENV);
+        let trigger_plugins = TriggerPlugins::from_env()
+            .map_err(|e| log::warn!("trigger plugins are disabled: {e:?}"))
+            .unwrap_or_default();
+        Self {
+            trigger_plugins,
+            ..Self::new(precompile_key, trust_precompiled_layers)
+        }
+    }
+}
+
+impl SpinEngine {
+    pub(crate) fn new(
+        precompile_key: Option<PrecompileKey>,
+        trust_precompiled_layers: bool,
+    ) -> Self {
         // the host expects epoch interruption to be enabled, so this has to be
         // turned on for the components we compile.
         let mut config = wasmtime::Config::default();
@@ -51,7 +80,41 @@ impl Default for SpinEngine {
         config.native_unwind_info(false);
         Self {
             wasmtime_engine: wasmtime::Engine::new(&config).unwrap(),
+            precompile_key,
+            trust_precompiled_layers,
+            image: None,
+            trigger_plugins: TriggerPlugins::default(),
+        }
+    }
+
+    /// Returns the content of a wasm layer that may be handed to the component loader.
+    ///
+    /// Layers sealed by this node are returned without their seal. Unsealed
+    /// precompiled layers are only accepted if precompiled layers are trusted.
+    pub(crate) fn verify_wasm_layer<'a>(&self, layer: &'a WasmLayer) -> Result<&'a [u8]> {
+        let digest = layer.config.digest();
+        if let Some(key) = &self.precompile_key {
+            if let Some(artifact) = key
+                .unseal(&layer.layer)
+                .with_context(|| format!("failed to verify layer {digest}"))?
+            {
+                return Ok(artifact);
+            }
+        }
+        if self
+            .wasmtime_engine
+            .detect_precompiled(&layer.layer)
+            .is_some()
+        {
+            if !self.trust_precompiled_layers {
+                bail!(
+                    "layer {digest} contains precompiled code that was not compiled by this node; set {}=true on the shim to trust precompiled layers",
+                    constants::SPIN_TRUST_PRECOMPILED_LAYERS_ENV
+                );
+            }
+            log::warn!("loading untrusted precompiled layer {:?}", digest);
         }
+        Ok(&layer.layer)
     }
 }
 
@@ -63,22 +126,46 @@ impl Engine for SpinEngine {
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
         stdio.redirect()?;
         info!("setting up wasi");
+        let mut config = AppConfig::from_env()?;
+        if let containerd_shim_wasm::container::Source::Oci(_) = ctx.entrypoint().source {
+            if let Some(image) = ImageReference::from_env_or(self.image.as_ref()) {
+                config.add_image_resource_attributes(&image);
+            }
+        }
         let rt = Runtime::new().context("failed to create runtime")?;
 
-        let (abortable, abort_handle) = futures::future::abortable(self.wasm_exec_async(ctx));
-        ctrlc::set_handler(move || abort_handle.abort())?;
+        let drain_period = config.drain_period;
 
-        match rt.block_on(abortable) {
-            Ok(Ok(())) => {
-                info!("run_wasi shut down: exiting");
-                Ok(0)
+        let (signal_tx, mut signals) = mpsc::unbounded_channel();
+        ctrlc::set_handler(move || {
+            let _ = signal_tx.send(());
+        })?;
+
+        let shutdown = Shutdown::default();
+        let exec_result = rt.block_on(async {
+            let mut exec = Box::pin(self.wasm_exec_async(ctx, config, &shutdown));
+            if let Either::Left((result, _)) =
+                future::select(exec.as_mut(), Box::pin(signals.recv())).await
+            {
+                return Some(result);
+            }
+            // The triggers stop taking on new work and exit once their in-flight work is done
+            shutdown.stop();
+            drain(exec, drain_period, &mut signals).await;
+            None
+        });
+
+        match exec_result {
+            Some(Ok(exit_code)) => {
+                info!("run_wasi shut down: exiting with code {exit_code}");
+                Ok(exit_code)
             }
-            Ok(Err(err)) => {
+            Some(Err(err)) => {
                 log::error!("run_wasi ERROR >>>  failed: {:?}", err);
                 Err(err)
             }
-            Err(aborted) => {
-                info!("Received signal to abort: {:?}", aborted);
+            None => {
+                info!("Received signal to abort");
                 Ok(0)
             }
         }
@@ -98,6 +185,10 @@ impl Engine for SpinEngine {
     }
 
     fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
+        let key = self
+            .precompile_key
+            .as_ref()
+            .context("precompilation is disabled: no precompile key available")?;
         // Runwasi expects layers to be returned in the same order, so wrap each layer in an option, setting non Wasm layers to None
         let precompiled_layers = layers
             .iter()
@@ -112,13 +203,21 @@ impl Engine for SpinEngine {
                         .detect_precompiled(&wasm_layer.layer)
                         .is_some()
                     {
+                        // Precompiled layers are native code supplied by the image author
+                        if !self.trust_precompiled_layers {
+                            bail!(
+                                "layer {} is already precompiled; set {}=true on the shim to trust precompiled layers",
+                                wasm_layer.config.digest(),
+                                constants::SPIN_TRUST_PRECOMPILED_LAYERS_ENV
+                            );
+                        }
                         log::info!("Layer already precompiled {:?}", wasm_layer.config.digest());
-                        Ok(Some(wasm_layer.layer))
+                        Ok(Some(key.seal(wasm_layer.layer)))
                     } else {
                         let component =
                             spin_componentize::componentize_if_necessary(&wasm_layer.layer)?;
                         let precompiled = self.wasmtime_engine.precompile_component(&component)?;
-                        Ok(Some(precompiled))
+                        Ok(Some(key.seal(precompiled)))
                     }
                 }
                 None => Ok(None),
@@ -128,108 +227,152 @@ impl Engine for SpinEngine {
     }
 
     fn can_precompile(&self) -> Option<String> {
+        let key = self.precompile_key.as_ref()?;
         let mut hasher = DefaultHasher::new();
         self.wasmtime_engine
             .precompile_compatibility_hash()
             .hash(&mut hasher);
+        // Artifacts sealed with a previous key must be recompiled
+        key.id().hash(&mut hasher);
         Some(hasher.finish().to_string())
     }
 }
 
 impl SpinEngine {
-    async fn wasm_exec_async(&self, ctx: &impl RuntimeContext) -> Result<()> {
-        let cache = initialize_cache().await?;
-        let app_source = Source::from_ctx(ctx, &cache).await?;
-        let mut locked_app = app_source.to_locked_app(&cache).await?;
-        let components_to_execute = env::var(constants::SPIN_COMPONENTS_TO_RETAIN_ENV)
-            .ok()
-            .map(|s| s.split(',').map(|s| s.to_string()).collect::<Vec<String>>());
-        if let Some(components) = components_to_execute {
-            if let Err(e) = crate::retain::retain_components(&mut locked_app, &components) {
+    async fn wasm_exec_async(
+        &self,
+        ctx: &impl RuntimeContext,
+        mut config: AppConfig,
+        shutdown: &Shutdown,
+    ) -> Result<i32> {
+        config.ensure_writable()?;
+        let cache = initialize_cache(&config.cache_dir).await?;
+        let app_source = Source::from_ctx(ctx, &cache, &config, self).await?;
+        let mut locked_app = app_source.to_locked_app(&cache, &config).await?;
+        // Checked before selective deployment drops the variables of dropped components
+        for name in config.variables.undeclared_mapped_variables(&locked_app) {
+            log::warn!(
+                " >>> {} maps variable {name:?}, which the app does not declare",
+                constants::SPIN_VARIABLES_MAP_ENV
+            );
+        }
+        let mut triggers = TriggerRegistry::builtin();
+        triggers.register_plugins(self.trigger_plugins.resolve()?);
+        if config.components_to_retain.is_some() || config.components_to_exclude.is_some() {
+            let retained = crate::retain::select_components(
+                &locked_app,
+                config.components_to_retain.as_deref(),
+                config.components_to_exclude.as_deref().unwrap_or_default(),
+            )
+            .and_then(|components| {
+                crate::retain::retain_components(&mut locked_app, &components, &config.variables)
+            });
+            if let Err(e) = retained {
                 println!("Error with selective deployment: {:?}", e);
                 return Err(e);
             }
         }
-        configure_application_variables_from_environment_variables(&locked_app)?;
-        let trigger_cmds = get_supported_triggers(&locked_app)
+        if let Some(trigger_types) = &config.triggers_to_run {
+            crate::retain::retain_triggers(&mut locked_app, trigger_types, &config.variables)?;
+        }
+        if config.skip_unsupported_triggers {
+            crate::retain::retain_supported_triggers(
+                &mut locked_app,
+                &triggers.trigger_types(),
+                &config.variables,
+            )?;
+        }
+        if let Some(secrets_dir) = config.variables.secrets_dir() {
+            info!(" >>> reading application variables from {:?}", secrets_dir);
+        }
+        let trigger_cmds = triggers
+            .app_trigger_types(&locked_app)
             .with_context(|| format!("Couldn't find trigger executor for {app_source:?}"))?;
-        let _telemetry_guard = spin_telemetry::init(version!().to_string())?;
+        let runtime_config = resolve_runtime_config(&config)?;
+        runtime_config.apply_trigger_settings(&mut locked_app)?;
+        check_required_variables(
+            &locked_app,
+            &config.variables,
+            runtime_config.configures_variables_providers(),
+        )?;
+        config.runtime_config_file = runtime_config.file;
+        let _telemetry_guard = telemetry::init(&config, version!())?;
 
-        self.run_trigger(ctx, &trigger_cmds, locked_app, app_source)
-            .await
+        self.run_trigger(
+            ctx,
+            &triggers,
+            &trigger_cmds,
+            locked_app,
+            app_source,
+            &config,
+            shutdown,
+        )
+        .await
     }
 
     async fn run_trigger(
         &self,
         ctx: &impl RuntimeContext,
+        triggers: &TriggerRegistry,
         trigger_types: &HashSet<String>,
         app: LockedApp,
         app_source: Source,
-    ) -> Result<()> {
-        let mut loader = spin_trigger::loader::ComponentLoader::default();
-        match app_source {
-            Source::Oci => unsafe {
-                // Configure the loader to support loading AOT compiled components..
-                // Since all components were compiled by the shim (during `precompile`),
-                // this operation can be considered safe.
-                loader.enable_loading_aot_compiled_components();
-            },
-            // Currently, it is only possible to precompile applications distributed using
-            // `spin registry push`
-            Source::File(_) => {}
-        };
-
-        let mut futures_list = Vec::new();
-        let mut trigger_type_map = Vec::new();
-        // The `HOSTNAME` environment variable should contain the fully unique container name
-        let app_id = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into());
-        for trigger_type in trigger_types.iter() {
-            let app = spin_app::App::new(&app_id, app.clone());
-            let f = match trigger_type.as_str() {
-                HTTP_TRIGGER_TYPE => {
-                    let address_str = env::var(constants::SPIN_HTTP_LISTEN_ADDR_ENV)
-                        .unwrap_or_else(|_| constants::SPIN_ADDR_DEFAULT.to_string());
-                    let address = parse_addr(&address_str)?;
-                    let cli_args = spin_trigger_http::CliArgs {
-                        address,
-                        tls_cert: None,
-                        tls_key: None,
-                    };
-                    trigger::run::<HttpTrigger>(cli_args, app, &loader).await?
-                }
-                REDIS_TRIGGER_TYPE => trigger::run::<RedisTrigger>(NoCliArgs, app, &loader).await?,
-                SQS_TRIGGER_TYPE => trigger::run::<SqsTrigger>(NoCliArgs, app, &loader).await?,
-                COMMAND_TRIGGER_TYPE => {
-                    let cli_args = trigger_command::CliArgs {
-                        guest_args: ctx.args().to_vec(),
-                    };
-                    trigger::run::<CommandTrigger>(cli_args, app, &loader).await?
-                }
-                MQTT_TRIGGER_TYPE => {
-                    let cli_args = trigger_mqtt::CliArgs { test: false };
-                    trigger::run::<MqttTrigger>(cli_args, app, &loader).await?
-                }
-                _ => {
-                    // This should never happen as we check for supported triggers in get_supported_triggers
-                    unreachable!()
-                }
+        config: &AppConfig,
+        shutdown: &Shutdown,
+    ) -> Result<i32> {
+        let loader = trigger::component_loader(&app_source);
+        let start = |trigger_type: String| -> LocalBoxFuture<'_, Result<TriggerFuture>> {
+            let trigger_ctx = TriggerContext {
+                app_id: &config.app_id,
+                locked_app: &app,
+                app_source: &app_source,
+                loader: &loader,
+                config,
+                #[cfg(feature = "command")]
+                args: ctx.args(),
+                shutdown,
             };
+            Box::pin(async move { triggers.start(&trigger_type, trigger_ctx).await })
+        };
+        exit_policy::run_triggers(
+            config.exit_policy,
+            trigger_types.iter().cloned(),
+            shutdown,
+            start,
+            |trigger_type, result| triggers.exit_code(trigger_type, result),
+            |trigger_type| triggers.restartable(trigger_type),
+        )
+        .await
+    }
+}
 
-            trigger_type_map.push(trigger_type.clone());
-            futures_list.push(f);
+/// Runs the triggers until they drained their in-flight work after the
+/// shutdown started.
+///
+/// Returns once all triggers exited, the drain period elapses or another
+/// termination signal is received, whichever happens first.
+async fn drain(
+    triggers: impl Future<Output = Result<i32>>,
+    drain_period: Duration,
+    signals: &mut mpsc::UnboundedReceiver<()>,
+) {
+    if drain_period.is_zero() {
+        return;
+    }
+    info!(" >>> draining in-flight work for up to {:?}", drain_period);
+    let drained = async {
+        match triggers.await {
+            Ok(_) => info!(" >>> in-flight work drained"),
+            Err(e) => log::error!(" >>> failed to drain in-flight work: {e:?}"),
         }
-
-        info!(" >>> notifying main thread we are about to start");
-
-        // exit as soon as any of the trigger completes/exits
-        let (result, index, rest) = future::select_all(futures_list).await;
-        let trigger_type = &trigger_type_map[index];
-
-        info!(" >>> trigger type '{trigger_type}' exited");
-
-        drop(rest);
-
-        result
+    };
+    let forced = async {
+        signals.recv().await;
+        info!(" >>> received another signal, aborting in-flight work");
+    };
+    let drained = future::select(Box::pin(drained), Box::pin(forced));
+    if tokio::time::timeout(drain_period, drained).await.is_err() {
+        info!(" >>> drain period elapsed, aborting in-flight work");
     }
 }
 
@@ -239,53 +382,125 @@ mod tests {
 
     use super::*;
 
-    #[test]
-    fn precompile() {
-        let module = wat::parse_str("(module)").unwrap();
+    fn wasm_layer(layer: Vec<u8>, media_type: &str) -> WasmLayer {
+        WasmLayer {
+            layer,
+            config: oci_spec::image::Descriptor::new(
+                MediaType::Other(media_type.to_string()),
+                1024,
+                "sha256:1234",
+            ),
+        }
+    }
+
+    fn precompiled_component() -> Vec<u8> {
         let wasmtime_engine = wasmtime::Engine::default();
-        let component = wasmtime::component::Component::new(&wasmtime_engine, "(component)")
+        wasmtime::component::Component::new(&wasmtime_engine, "(component)")
             .unwrap()
             .serialize()
-            .unwrap();
+            .unwrap()
+    }
+
+    /// Work that finishes after the given time, recording that it finished
+    async fn in_flight_work(duration: Duration, finished: &std::cell::Cell<bool>) -> Result<i32> {
+        tokio::time::sleep(duration).await;
+        finished.set(true);
+        Ok(0)
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_in_flight_work() {
+        let (_signal_tx, mut signals) = mpsc::unbounded_channel();
+        let finished = Default::default();
+        let work = in_flight_work(Duration::from_millis(50), &finished);
+        let started = std::time::Instant::now();
+        drain(work, Duration::from_secs(60), &mut signals).await;
+        assert!(finished.get());
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn drain_is_aborted_by_another_signal() {
+        let (signal_tx, mut signals) = mpsc::unbounded_channel();
+        let finished = Default::default();
+        let work = in_flight_work(Duration::from_secs(60), &finished);
+        signal_tx.send(()).unwrap();
+        drain(work, Duration::from_secs(60), &mut signals).await;
+        assert!(!finished.get());
+    }
+
+    #[tokio::test]
+    async fn drain_is_bounded_by_drain_period() {
+        let (_signal_tx, mut signals) = mpsc::unbounded_channel();
+        let finished = Default::default();
+        let work = in_flight_work(Duration::from_secs(60), &finished);
+        drain(work, Duration::from_millis(50), &mut signals).await;
+        assert!(!finished.get());
+    }
+
+    #[test]
+    fn precompile() {
+        let module = wat::parse_str("(module)").unwrap();
+        let component = precompiled_component();
         let wasm_layers: Vec<WasmLayer> = vec![
             // Needs to be precompiled
-            WasmLayer {
-                layer: module.clone(),
-                config: oci_spec::image::Descriptor::new(
-                    MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
-                    1024,
-                    "sha256:1234",
-                ),
-            },
+            wasm_layer(module.clone(), constants::OCI_LAYER_MEDIA_TYPE_WASM),
             // Precompiled
-            WasmLayer {
-                layer: component.to_owned(),
-                config: oci_spec::image::Descriptor::new(
-                    MediaType::Other(constants::OCI_LAYER_MEDIA_TYPE_WASM.to_string()),
-                    1024,
-                    "sha256:1234",
-                ),
-            },
+            wasm_layer(component.clone(), constants::OCI_LAYER_MEDIA_TYPE_WASM),
             // Content that should be skipped
-            WasmLayer {
-                layer: vec![],
-                config: oci_spec::image::Descriptor::new(
-                    MediaType::Other(spin_oci::client::DATA_MEDIATYPE.to_string()),
-                    1024,
-                    "sha256:1234",
-                ),
-            },
+            wasm_layer(vec![], spin_oci::client::DATA_MEDIATYPE),
         ];
-        let spin_engine = SpinEngine::default();
+        let spin_engine = SpinEngine::new(Some(PrecompileKey::new([1; 32])), true);
         let precompiled = spin_engine
             .precompile(&wasm_layers)
             .expect("precompile failed");
         assert_eq!(precompiled.len(), 3);
-        assert_ne!(precompiled[0].as_deref().expect("no first entry"), module);
-        assert_eq!(
-            precompiled[1].as_deref().expect("no second entry"),
-            component
+        let first = wasm_layer(
+            precompiled[0].clone().expect("no first entry"),
+            constants::OCI_LAYER_MEDIA_TYPE_WASM,
+        );
+        let first = spin_engine.verify_wasm_layer(&first).unwrap();
+        assert_ne!(first, module);
+        assert!(spin_engine
+            .wasmtime_engine
+            .detect_precompiled(first)
+            .is_some());
+        let second = wasm_layer(
+            precompiled[1].clone().expect("no second entry"),
+            constants::OCI_LAYER_MEDIA_TYPE_WASM,
         );
+        assert_eq!(spin_engine.verify_wasm_layer(&second).unwrap(), component);
         assert!(precompiled[2].is_none());
     }
+
+    #[test]
+    fn precompile_rejects_untrusted_precompiled_layers() {
+        let wasm_layers = vec![wasm_layer(
+            precompiled_component(),
+            constants::OCI_LAYER_MEDIA_TYPE_WASM,
+        )];
+        let spin_engine = SpinEngine::new(Some(PrecompileKey::new([1; 32])), false);
+        assert!(spin_engine.precompile(&wasm_layers).is_err());
+    }
+
+    #[test]
+    fn verify_wasm_layer() {
+        let module = wat::parse_str("(module)").unwrap();
+        let component = precompiled_component();
+        let spin_engine = SpinEngine::new(Some(PrecompileKey::new([1; 32])), false);
+        // Wasm that has not been precompiled is always accepted
+        let layer = wasm_layer(module.clone(), constants::OCI_LAYER_MEDIA_TYPE_WASM);
+        assert_eq!(spin_engine.verify_wasm_layer(&layer).unwrap(), module);
+        // Precompiled code from the image is rejected unless trusted
+        let layer = wasm_layer(component.clone(), constants::OCI_LAYER_MEDIA_TYPE_WASM);
+        assert!(spin_engine.verify_wasm_layer(&layer).is_err());
+        // Precompiled code sealed by another node is rejected
+        let sealed = PrecompileKey::new([2; 32]).seal(component.clone());
+        let layer = wasm_layer(sealed, constants::OCI_LAYER_MEDIA_TYPE_WASM);
+        assert!(spin_engine.verify_wasm_layer(&layer).is_err());
+        // Precompiled code sealed by this node is accepted
+        let sealed = PrecompileKey::new([1; 32]).seal(component.clone());
+        let layer = wasm_layer(sealed, constants::OCI_LAYER_MEDIA_TYPE_WASM);
+        assert_eq!(spin_engine.verify_wasm_layer(&layer).unwrap(), component);
+    }
 }
@@ -0,0 +1,432 @@
+//! This module contains the logic for running the triggers of an app according to an exit policy
+//!
+//! The policy is set through the `SPIN_TRIGGER_EXIT_POLICY` environment
+//! variable of the container and decides what happens when a trigger exits:
+//!
+//! - `first-exit` (default): the app stops as soon as any trigger exits.
+//! - `wait-all`: the app stops once all triggers exited.
+//! - `restart`: failed triggers are restarted with exponential backoff, and the
+//!   app stops once all triggers exited successfully. Triggers that run to
+//!   completion, such as the command trigger, are not restarted: their failure
+//!   is the outcome of the app, as with `wait-all`, so that `Job`s do not
+//!   retry forever.
+//!
+//! The policy no longer applies once the shim is shutting down: triggers are
+//! no longer started or restarted, and the triggers that exit after draining
+//! their in-flight work do not stop the others.
+
+use std::{
+    collections::HashMap,
+    env,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use futures::{
+    future::LocalBoxFuture,
+    stream::{FuturesUnordered, StreamExt},
+};
+use log::info;
+
+use crate::{constants, shutdown::Shutdown, trigger::TriggerFuture};
+
+/// What happens when a trigger exits
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum ExitPolicy {
+    /// Stop all triggers when any trigger exits
+    #[default]
+    FirstExit,
+    /// Keep the other triggers running until all triggers exited
+    WaitAll,
+    /// Restart triggers that failed
+    Restart(Backoff),
+}
+
+impl ExitPolicy {
+    /// Returns the policy configured through the `SPIN_TRIGGER_EXIT_POLICY`
+    /// environment variable of the container, if any.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var(constants::SPIN_TRIGGER_EXIT_POLICY_ENV) {
+            Ok(policy) => policy.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+impl FromStr for ExitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "first-exit" => Ok(Self::FirstExit),
+            "wait-all" => Ok(Self::WaitAll),
+            "restart" => Ok(Self::Restart(Backoff::default())),
+            other => bail!(
+                "invalid {}: {other:?} is not one of first-exit, wait-all or restart",
+                constants::SPIN_TRIGGER_EXIT_POLICY_ENV
+            ),
+        }
+    }
+}
+
+/// Exponentially growing delays between retries, such as restarts of a failed
+/// trigger
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(
+            constants::SPIN_TRIGGER_RESTART_BACKOFF_INITIAL,
+            constants::SPIN_TRIGGER_RESTART_BACKOFF_MAX,
+        )
+    }
+}
+
+impl Backoff {
+    pub(crate) const fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+
+    /// Returns the delay before retrying something that failed the given
+    /// number of consecutive times.
+    pub(crate) fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// How a trigger exited
+struct Exit {
+    trigger_type: String,
+    result: Result<()>,
+    /// How long the trigger ran before it exited
+    uptime: Duration,
+}
+
+/// Runs a started trigger until it exits
+async fn run(trigger_type: String, trigger: TriggerFuture) -> Exit {
+    let started = Instant::now();
+    let result = trigger.await;
+    Exit {
+        trigger_type,
+        result,
+        uptime: started.elapsed(),
+    }
+}
+
+/// Starts the triggers of the given types and runs them according to the
+/// policy, returning the exit code of the container.
+///
+/// Triggers that fail to start initially fail the app, regardless of the
+/// policy. Only triggers that are `restartable` are restarted. The exit code is
+/// mapped from the result of the trigger that stopped the app or, if all
+/// triggers exited, from the result of the first trigger that failed. Once the
+/// shutdown started, all triggers are run until they exited.
+pub(crate) async fn run_triggers<'a, S, E, R>(
+    policy: ExitPolicy,
+    trigger_types: impl IntoIterator<Item = String>,
+    shutdown: &Shutdown,
+    start: S,
+    exit_code: E,
+    restartable: R,
+) -> Result<i32>
+where
+    S: Fn(String) -> LocalBoxFuture<'a, Result<TriggerFuture>>,
+    E: Fn(&str, Result<()>) -> Result<i32>,
+    R: Fn(&str) -> bool,
+{
+    let mut running = FuturesUnordered::<LocalBoxFuture<'_, Exit>>::new();
+    for trigger_type in trigger_types {
+        if shutdown.is_stopping() {
+            break;
+        }
+        let trigger = start(trigger_type.clone()).await?;
+        running.push(Box::pin(run(trigger_type, trigger)));
+    }
+
+    info!(" >>> notifying main thread we are about to start");
+
+    let start = &start;
+    let mut consecutive_failures = HashMap::new();
+    let mut outcome: Option<Result<i32>> = None;
+    while let Some(exit) = running.next().await {
+        let Exit {
+            trigger_type,
+            result,
+            uptime,
+        } = exit;
+        match &result {
+            Ok(()) => info!(" >>> trigger type '{trigger_type}' exited after {uptime:?}"),
+            Err(e) => {
+                log::error!(" >>> trigger type '{trigger_type}' failed after {uptime:?}: {e:?}")
+            }
+        }
+        if shutdown.is_stopping() {
+            continue;
+        }
+        match policy {
+            ExitPolicy::FirstExit => {
+                if !running.is_empty() {
+                    info!(" >>> stopping the other triggers");
+                }
+                return exit_code(&trigger_type, result);
+            }
+            ExitPolicy::Restart(backoff) if result.is_err() && restartable(&trigger_type) => {
+                let failures = consecutive_failures
+                    .entry(trigger_type.clone())
+                    .or_insert(0);
+                // A trigger that ran for a while is not failing repeatedly
+                if uptime >= backoff.max {
+                    *failures = 0;
+                }
+                *failures += 1;
+                let delay = backoff.delay(*failures);
+                info!(" >>> restarting trigger type '{trigger_type}' in {delay:?}");
+                running.push(Box::pin(async move {
+                    if shutdown
+                        .unless_stopped(tokio::time::sleep(delay))
+                        .await
+                        .is_none()
+                    {
+                        return Exit {
+                            trigger_type,
+                            result: Ok(()),
+                            uptime: Duration::ZERO,
+                        };
+                    }
+                    match start(trigger_type.clone()).await {
+                        Ok(trigger) => run(trigger_type, trigger).await,
+                        Err(e) => Exit {
+                            trigger_type,
+                            result: Err(e.context("failed to restart trigger")),
+                            uptime: Duration::ZERO,
+                        },
+                    }
+                }));
+            }
+            ExitPolicy::WaitAll | ExitPolicy::Restart(_) => {
+                let code = exit_code(&trigger_type, result);
+                if !outcome.as_ref().is_some_and(is_failure) {
+                    outcome = Some(code);
+                }
+            }
+        }
+    }
+    outcome.unwrap_or(Ok(0))
+}
+
+fn is_failure(code: &Result<i32>) -> bool {
+    !matches!(code, Ok(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Starts fake triggers, whose results are given in order per trigger
+    /// type, and records which triggers were started.
+    struct FakeTriggers {
+        results: RefCell<HashMap<String, Vec<Result<()>>>>,
+        started: RefCell<Vec<String>>,
+    }
+
+    impl FakeTriggers {
+        fn new(results: impl IntoIterator<Item = (&'static str, Vec<Result<()>>)>) -> Self {
+            Self {
+                results: RefCell::new(
+                    results
+                        .into_iter()
+                        .map(|(trigger_type, results)| (trigger_type.to_string(), results))
+                        .collect(),
+                ),
+                started: Default::default(),
+            }
+        }
+
+        /// Starts the trigger, which never exits once it ran out of results
+        fn start(&self, trigger_type: String) -> LocalBoxFuture<'_, Result<TriggerFuture>> {
+            self.started.borrow_mut().push(trigger_type.clone());
+            let mut results = self.results.borrow_mut();
+            let results = results.get_mut(&trigger_type).unwrap();
+            let result = (!results.is_empty()).then(|| results.remove(0));
+            Box::pin(async move {
+                Ok(Box::pin(async move {
+                    match result {
+                        Some(result) => {
+                            tokio::task::yield_now().await;
+                            result
+                        }
+                        None => futures::future::pending().await,
+                    }
+                }) as TriggerFuture)
+            })
+        }
+
+        fn started(&self) -> Vec<String> {
+            self.started.borrow().clone()
+        }
+    }
+
+    fn exit_code(_trigger_type: &str, result: Result<()>) -> Result<i32> {
+        result.map(|()| 0)
+    }
+
+    fn fast_backoff() -> ExitPolicy {
+        ExitPolicy::Restart(Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+        })
+    }
+
+    async fn run(
+        policy: ExitPolicy,
+        triggers: &FakeTriggers,
+        trigger_types: &[&str],
+    ) -> Result<i32> {
+        run_triggers(
+            policy,
+            trigger_types.iter().map(|t| t.to_string()),
+            &Shutdown::default(),
+            |t| triggers.start(t),
+            exit_code,
+            |t| t != "command",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn first_exit_stops_all_triggers() {
+        let triggers = FakeTriggers::new([
+            ("redis", vec![Err(anyhow!("disconnected"))]),
+            ("http", vec![]),
+        ]);
+        let result = run(ExitPolicy::FirstExit, &triggers, &["redis", "http"]).await;
+        assert_eq!(result.unwrap_err().to_string(), "disconnected");
+    }
+
+    #[tokio::test]
+    async fn wait_all_waits_for_all_triggers() {
+        let triggers = FakeTriggers::new([
+            ("redis", vec![Err(anyhow!("disconnected"))]),
+            ("command", vec![Ok(())]),
+        ]);
+        let result = run(ExitPolicy::WaitAll, &triggers, &["redis", "command"]).await;
+        assert_eq!(result.unwrap_err().to_string(), "disconnected");
+
+        let triggers = FakeTriggers::new([("cron", vec![Ok(())]), ("command", vec![Ok(())])]);
+        let result = run(ExitPolicy::WaitAll, &triggers, &["cron", "command"]).await;
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn restart_restarts_failed_triggers() {
+        let triggers = FakeTriggers::new([
+            (
+                "redis",
+                vec![
+                    Err(anyhow!("disconnected")),
+                    Err(anyhow!("disconnected")),
+                    Ok(()),
+                ],
+            ),
+            ("command", vec![Ok(())]),
+        ]);
+        let result = run(fast_backoff(), &triggers, &["redis", "command"]).await;
+        assert_eq!(result.unwrap(), 0);
+        let mut started = triggers.started();
+        started.sort();
+        assert_eq!(started, ["command", "redis", "redis", "redis"]);
+    }
+
+    #[tokio::test]
+    async fn restart_does_not_restart_triggers_that_run_to_completion() {
+        let triggers = FakeTriggers::new([
+            ("command", vec![Err(anyhow!("trapped")), Ok(())]),
+            ("cron", vec![Ok(())]),
+        ]);
+        let result = run(fast_backoff(), &triggers, &["command", "cron"]).await;
+        assert_eq!(result.unwrap_err().to_string(), "trapped");
+        let mut started = triggers.started();
+        started.sort();
+        assert_eq!(started, ["command", "cron"]);
+    }
+
+    #[tokio::test]
+    async fn policy_does_not_apply_on_shutdown() {
+        for policy in [ExitPolicy::FirstExit, fast_backoff()] {
+            let triggers = FakeTriggers::new([
+                ("redis", vec![Err(anyhow!("disconnected"))]),
+                ("cron", vec![Ok(())]),
+            ]);
+            let shutdown = Shutdown::default();
+            let result = run_triggers(
+                policy,
+                ["redis".to_string(), "cron".to_string()],
+                &shutdown,
+                |t| {
+                    let trigger = triggers.start(t);
+                    // The shutdown starts once all triggers are running
+                    if triggers.started().len() == 2 {
+                        shutdown.stop();
+                    }
+                    trigger
+                },
+                exit_code,
+                |_| true,
+            )
+            .await;
+            // The failed trigger neither stops the app nor is restarted
+            assert_eq!(result.unwrap(), 0);
+            assert_eq!(triggers.started(), ["redis", "cron"]);
+        }
+
+        // Triggers are not started once the shutdown started
+        let triggers = FakeTriggers::new([("redis", vec![])]);
+        let shutdown = Shutdown::default();
+        shutdown.stop();
+        let result = run_triggers(
+            ExitPolicy::FirstExit,
+            ["redis".to_string()],
+            &shutdown,
+            |t| triggers.start(t),
+            exit_code,
+            |_| true,
+        )
+        .await;
+        assert_eq!(result.unwrap(), 0);
+        assert!(triggers.started().is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Backoff::default();
+        let delays = [1, 2, 3, 7, 8, 100].map(|failures| backoff.delay(failures).as_secs());
+        assert_eq!(delays, [1, 2, 4, 60, 60, 60]);
+    }
+
+    #[test]
+    fn parse_exit_policy() {
+        assert_eq!(
+            "first-exit".parse::<ExitPolicy>().unwrap(),
+            ExitPolicy::FirstExit
+        );
+        assert_eq!(
+            "wait-all".parse::<ExitPolicy>().unwrap(),
+            ExitPolicy::WaitAll
+        );
+        assert_eq!(
+            "restart".parse::<ExitPolicy>().unwrap(),
+            ExitPolicy::Restart(Backoff::default())
+        );
+        assert!("restart-always".parse::<ExitPolicy>().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! This module contains the HTTP trigger
+//!
+//! The shim accepts connections itself, terminating TLS if certificate files
+//! are configured, and hands the requests to Spin's HTTP server. The listener
+//! is bound once, so that the certificates of rotated secrets are served
+//! without refusing connections, see [`crate::tls`], and the connections are
+//! tracked, so that the trigger exits as soon as it drained on shutdown.
+
+use std::{env, net::SocketAddr, pin::pin, sync::Arc};
+
+use anyhow::{Context, Result};
+use futures::future::{self, Either, LocalBoxFuture};
+use http::uri::Scheme;
+use http_body_util::BodyExt;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use log::info;
+use spin_runtime_factors::TriggerFactors;
+use spin_trigger_http::{HttpServer, HttpTrigger};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    constants,
+    registry::{TriggerContext, TriggerRunner},
+    shutdown::{InFlight, Shutdown},
+    tls::{self, TlsFiles},
+    trigger::{self, TriggerFuture},
+    utils::parse_addr,
+    variables::FactorsArgs,
+};
+
+/// Settings of the HTTP trigger
+#[derive(Clone, Debug)]
+pub(crate) struct HttpConfig {
+    /// Address the trigger listens on
+    pub(crate) address: SocketAddr,
+    /// Certificate and key the trigger serves HTTPS with, if any
+    pub(crate) tls: Option<TlsFiles>,
+}
+
+impl HttpConfig {
+    /// Returns the settings from the `SPIN_HTTP_LISTEN_ADDR`,
+    /// `SPIN_HTTP_TLS_CERT` and `SPIN_HTTP_TLS_KEY` environment variables of
+    /// the container
+    pub(crate) fn from_env() -> Result<Self> {
+        let address = env::var(constants::SPIN_HTTP_LISTEN_ADDR_ENV)
+            .unwrap_or_else(|_| constants::SPIN_ADDR_DEFAULT.to_string());
+        Ok(Self {
+            address: parse_addr(&address)?,
+            tls: TlsFiles::from_env()?,
+        })
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            address: constants::SPIN_ADDR_DEFAULT
+                .parse()
+                .expect("default address is valid"),
+            tls: None,
+        }
+    }
+}
+
+/// Runs the HTTP trigger on the configured address, over TLS if certificate
+/// files are configured.
+pub(crate) struct HttpTriggerRunner;
+
+impl TriggerRunner for HttpTriggerRunner {
+    fn start<'a>(&'a self, ctx: TriggerContext<'a>) -> LocalBoxFuture<'a, Result<TriggerFuture>> {
+        Box::pin(async move {
+            let address = ctx.config.http.address;
+            let tls = ctx.config.http.tls.clone().map(tls::acceptor).transpose()?;
+            let server = http_server(&ctx, address).await?;
+            let shutdown = ctx.shutdown.clone();
+            let running: TriggerFuture = match tls {
+                Some((acceptor, reload)) => Box::pin(async move {
+                    let serving = serve(server, address, Some(acceptor), shutdown);
+                    match future::select(Box::pin(serving), Box::pin(reload)).await {
+                        Either::Left((result, _)) => result,
+                        Either::Right((never, _)) => match never {},
+                    }
+                }),
+                None => Box::pin(serve(server, address, None, shutdown)),
+            };
+            Ok(running)
+        })
+    }
+}
+
+/// Builds Spin's HTTP server for the app, which routes the requests to the
+/// components without binding the address itself
+async fn http_server(
+    ctx: &TriggerContext<'_>,
+    address: SocketAddr,
+) -> Result<Arc<HttpServer<TriggerFactors>>> {
+    info!(" >>> running http trigger");
+    let app = ctx.app();
+    let trigger = HttpTrigger::new(&app, address, None)?;
+    let args = FactorsArgs::new(ctx.config.variables.clone());
+    let (trigger, trigger_app) = trigger::build(trigger, app, ctx.loader, ctx.config, args).await?;
+    trigger.into_server(trigger_app)
+}
+
+/// Accepts connections on the address until the shutdown starts, serving each
+/// in a task of its own, over TLS if an acceptor is given.
+///
+/// Once the shutdown started, the listener is closed and each connection is
+/// closed once the request in flight on it was served. Returns as soon as all
+/// connections were closed.
+async fn serve(
+    server: Arc<HttpServer<TriggerFactors>>,
+    address: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("unable to listen on {address}"))?;
+    let scheme = match acceptor {
+        Some(_) => Scheme::HTTPS,
+        None => Scheme::HTTP,
+    };
+    info!(" >>> serving {scheme}://{address}");
+    let connections = InFlight::default();
+    while let Some(accepted) = shutdown.unless_stopped(listener.accept()).await {
+        let (stream, client_addr) = accepted?;
+        let connection = connections.start();
+        let server = server.clone();
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match shutdown.unless_stopped(acceptor.accept(stream)).await {
+                    Some(Ok(stream)) => {
+                        serve_connection(server, stream, Scheme::HTTPS, client_addr, &shutdown)
+                            .await
+                    }
+                    Some(Err(e)) => {
+                        log::warn!(" >>> failed to start TLS session with {client_addr}: {e}")
+                    }
+                    None => {}
+                },
+                None => {
+                    serve_connection(server, stream, Scheme::HTTP, client_addr, &shutdown).await
+                }
+            }
+            drop(connection);
+        });
+    }
+    drop(listener);
+    info!(" >>> http trigger stopped accepting connections");
+    connections.finished().await;
+    Ok(())
+}
+
+/// Serves the requests of a connection with Spin's HTTP server until the
+/// client closes it, or the request in flight was served once the shutdown
+/// started
+async fn serve_connection<S>(
+    server: Arc<HttpServer<TriggerFactors>>,
+    stream: S,
+    scheme: Scheme,
+    client_addr: SocketAddr,
+    shutdown: &Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request: Request<Incoming>| {
+        let server = server.clone();
+        let scheme = scheme.clone();
+        async move {
+            let request = request.map(|body| {
+                body.map_err(wasmtime_wasi_http::hyper_response_error)
+                    .boxed()
+            });
+            server.handle(request, scheme, client_addr).await
+        }
+    });
+    let connection = http1::Builder::new()
+        .keep_alive(true)
+        .serve_connection(TokioIo::new(stream), service);
+    let mut connection = pin!(connection);
+    let result = match shutdown.unless_stopped(connection.as_mut()).await {
+        Some(result) => result,
+        None => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        log::warn!(" >>> failed to serve HTTP connection from {client_addr}: {e:?}");
+    }
+}
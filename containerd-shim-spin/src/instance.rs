@@ -0,0 +1,102 @@
+//! This module contains the instance the shim runs containers with
+//!
+//! It wraps the instance of runwasi to read the annotations of the container
+//! spec before the container is created, while the bundle is still available
+//! to the shim. The image of the container is passed to the engine that runs
+//! the container, so that OCI applications know where they come from.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
+use containerd_shim_wasm::{
+    container,
+    sandbox::{self, InstanceConfig},
+};
+use oci_spec::runtime::Spec;
+
+use crate::{constants, engine::SpinEngine, source::ImageReference};
+
+pub struct Instance(container::Instance<SpinEngine>);
+
+impl sandbox::Instance for Instance {
+    type Engine = SpinEngine;
+
+    fn new(id: String, cfg: Option<&InstanceConfig<SpinEngine>>) -> sandbox::Result<Self> {
+        let cfg = cfg.map(|cfg| {
+            let mut engine = cfg.get_engine();
+            engine.image = image_from_bundle(cfg.get_bundle());
+            let mut with_image =
+                InstanceConfig::new(engine, cfg.get_namespace(), cfg.get_containerd_address());
+            with_image
+                .set_stdin(cfg.get_stdin())
+                .set_stdout(cfg.get_stdout())
+                .set_stderr(cfg.get_stderr())
+                .set_bundle(cfg.get_bundle());
+            with_image
+        });
+        container::Instance::new(id, cfg.as_ref()).map(Self)
+    }
+
+    fn start(&self) -> sandbox::Result<u32> {
+        self.0.start()
+    }
+
+    fn kill(&self, signal: u32) -> sandbox::Result<()> {
+        self.0.kill(signal)
+    }
+
+    fn delete(&self) -> sandbox::Result<()> {
+        self.0.delete()
+    }
+
+    fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
+        self.0.wait_timeout(t)
+    }
+}
+
+/// Returns the image of the container from the annotations of its spec, if
+/// the container runtime set them
+fn image_from_bundle(bundle: &Path) -> Option<ImageReference> {
+    let spec = match Spec::load(bundle.join("config.json")) {
+        Ok(spec) => spec,
+        Err(e) => {
+            log::warn!("failed to read the container spec in {bundle:?}: {e}");
+            return None;
+        }
+    };
+    image_from_annotations(spec.annotations().as_ref()?)
+}
+
+fn image_from_annotations(annotations: &HashMap<String, String>) -> Option<ImageReference> {
+    annotations
+        .get(constants::CRI_IMAGE_NAME_ANNOTATION)
+        .filter(|name| !name.is_empty())
+        .map(|name| ImageReference::parse(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_is_read_from_cri_annotations() {
+        let annotations = HashMap::from([
+            (
+                "io.kubernetes.cri.container-type".to_string(),
+                "container".to_string(),
+            ),
+            (
+                constants::CRI_IMAGE_NAME_ANNOTATION.to_string(),
+                "ghcr.io/org/app:v1@sha256:1234".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            image_from_annotations(&annotations),
+            Some(ImageReference {
+                name: "ghcr.io/org/app:v1".to_string(),
+                digest: Some("sha256:1234".to_string()),
+            })
+        );
+        assert_eq!(image_from_annotations(&HashMap::new()), None);
+    }
+}
@@ -0,0 +1,391 @@
+//! This module contains the Kafka trigger, which delivers messages from Kafka topics to components
+//!
+//! It is configured like the other message triggers, see
+//! [`handle_message`]. The brokers are set with `brokers`, along with
+//! additional librdkafka client properties in `options`. Components are bound
+//! to topics, as members of a consumer group that may be shared by triggers
+//! through `group_id`:
+//!
+//! ```toml
+//! [[trigger.kafka]]
+//! component = "orders"
+//! topics = ["orders", "refunds"]
+//! group_id = "orders-processor"
+//! ```
+//!
+//! The offset of a message is only committed once the component handled it
+//! successfully. Otherwise the message is redelivered after a delay, which
+//! grows while the component keeps failing, holding back the later messages of
+//! its partition.
+
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::future;
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    ClientConfig, Message, Offset,
+};
+use serde::Deserialize;
+use spin_app::App;
+use spin_factors::RuntimeFactors;
+use spin_trigger::{Trigger, TriggerApp};
+
+use crate::{
+    shutdown::{Shutdown, ShutdownArgs},
+    trigger::{handle_message, REDELIVERY_BACKOFF},
+};
+
+/// How long seeking back to a failed message may block
+const SEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Application-level settings of the Kafka trigger
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KafkaTriggerMetadata {
+    /// Comma separated list of bootstrap brokers
+    brokers: Option<String>,
+    /// Consumer group of triggers that do not set their own
+    group_id: Option<String>,
+    /// Additional librdkafka client properties, such as security settings
+    #[serde(default)]
+    options: HashMap<String, String>,
+}
+
+/// Configuration of a Kafka trigger in the Spin manifest
+#[derive(Clone, Debug, Deserialize)]
+struct KafkaTriggerConfig {
+    component: String,
+    topics: Vec<String>,
+    #[serde(default)]
+    group_id: Option<String>,
+}
+
+pub(crate) struct KafkaTrigger {
+    consumers: Vec<(String, KafkaTriggerConfig, ClientConfig)>,
+    shutdown: Shutdown,
+}
+
+impl<F: RuntimeFactors> Trigger<F> for KafkaTrigger {
+    const TYPE: &'static str = "kafka";
+    type CliArgs = ShutdownArgs;
+    type InstanceState = ();
+
+    fn new(cli_args: Self::CliArgs, app: &App) -> Result<Self> {
+        let trigger_type = <Self as Trigger<F>>::TYPE;
+        let metadata = app
+            .get_trigger_metadata::<KafkaTriggerMetadata>(trigger_type)?
+            .unwrap_or_default();
+        let brokers = metadata.brokers.as_deref().context(
+            "no Kafka brokers configured: set `brokers` in the [trigger.kafka] table of the runtime config",
+        )?;
+        let consumers = app
+            .trigger_configs::<KafkaTriggerConfig>(trigger_type)?
+            .into_iter()
+            .map(|(id, config)| {
+                let group_id = config
+                    .group_id
+                    .as_deref()
+                    .or(metadata.group_id.as_deref())
+                    .with_context(|| {
+                        format!("no consumer group configured for Kafka trigger {id:?}")
+                    })?;
+                let mut client_config = ClientConfig::new();
+                for (key, value) in &metadata.options {
+                    client_config.set(key, value);
+                }
+                client_config
+                    .set("bootstrap.servers", brokers)
+                    .set("group.id", group_id)
+                    // Offsets are committed once a message was handled successfully
+                    .set("enable.auto.commit", "false");
+                Ok((id.to_string(), config, client_config))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            consumers,
+            shutdown: cli_args.shutdown,
+        })
+    }
+
+    async fn run(self, trigger_app: TriggerApp<Self, F>) -> Result<()> {
+        let trigger_app = &trigger_app;
+        let shutdown = &self.shutdown;
+        future::try_join_all(
+            self.consumers
+                .iter()
+                .map(|(id, config, client_config)| async move {
+                    consume(
+                        client_config,
+                        &config.topics,
+                        shutdown,
+                        |payload| async move {
+                            handle_message(trigger_app, &config.component, &payload)
+                                .await
+                                .with_context(|| {
+                                    format!("Kafka trigger {id:?} failed to handle message")
+                                })
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("Kafka trigger {id:?} failed"))
+                }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Passes each message of the topics to the handler until the shutdown starts.
+///
+/// The offset of a message is committed once the handler succeeded. If it
+/// failed, the consumer seeks back to the message, so that it is redelivered
+/// rather than skipped by later commits, and waits before receiving it again.
+async fn consume<H, Fut>(
+    client_config: &ClientConfig,
+    topics: &[String],
+    shutdown: &Shutdown,
+    mut handle: H,
+) -> Result<()>
+where
+    H: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let consumer: StreamConsumer = client_config
+        .create()
+        .context("failed to create Kafka consumer")?;
+    let topics = topics.iter().map(String::as_str).collect::<Vec<_>>();
+    consumer
+        .subscribe(&topics)
+        .with_context(|| format!("failed to subscribe to Kafka topics {topics:?}"))?;
+    log::info!(" >>> subscribed to Kafka topics {topics:?}");
+    // Consecutive failures to receive or handle a message
+    let mut failures = 0;
+    while let Some(received) = shutdown.unless_stopped(consumer.recv()).await {
+        let message = match received {
+            Ok(message) => message,
+            Err(e) => {
+                failures += 1;
+                let delay = REDELIVERY_BACKOFF.delay(failures);
+                log::error!(" >>> failed to receive Kafka message, retrying in {delay:?}: {e}");
+                if shutdown
+                    .unless_stopped(tokio::time::sleep(delay))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        let payload = message.payload().unwrap_or_default().to_vec();
+        match settlement(&message, handle(payload).await, &mut failures) {
+            Settlement::Commit => consumer
+                .commit_message(&message, CommitMode::Async)
+                .context("failed to commit Kafka offset")?,
+            Settlement::Redeliver(delay) => {
+                consumer
+                    .seek(
+                        message.topic(),
+                        message.partition(),
+                        Offset::Offset(message.offset()),
+                        SEEK_TIMEOUT,
+                    )
+                    .context("failed to seek back to the failed Kafka message")?;
+                if shutdown
+                    .unless_stopped(tokio::time::sleep(delay))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the consumer does with a message once the component handled it
+#[derive(Debug, PartialEq)]
+enum Settlement {
+    /// Commit the offset of the message
+    Commit,
+    /// Seek back to the message and receive it again after the delay
+    Redeliver(Duration),
+}
+
+/// Decides what becomes of a message once the component handled it. The
+/// consecutive failures are counted in `failures`, which the redelivery delay
+/// grows with.
+fn settlement(message: &impl Message, handled: Result<()>, failures: &mut u32) -> Settlement {
+    match handled {
+        Ok(()) => {
+            *failures = 0;
+            Settlement::Commit
+        }
+        Err(e) => {
+            *failures += 1;
+            let delay = REDELIVERY_BACKOFF.delay(*failures);
+            log::error!(
+                " >>> redelivering message at {}/{}/{} in {delay:?}: {e:?}",
+                message.topic(),
+                message.partition(),
+                message.offset()
+            );
+            Settlement::Redeliver(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::message::{OwnedMessage, Timestamp};
+    use spin_app::locked::LockedApp;
+    use spin_runtime_factors::TriggerFactors;
+
+    use super::*;
+    use crate::trigger::collect_calls;
+
+    fn kafka_app(metadata: serde_json::Value) -> App {
+        let locked_app = serde_json::json!({
+            "spin_lock_version": 1,
+            "metadata": {"triggers": {"kafka": metadata}},
+            "components": [],
+            "triggers": [
+                {
+                    "id": "orders",
+                    "trigger_type": "kafka",
+                    "trigger_config": {"component": "orders", "topics": ["orders"], "group_id": "orders-processor"}
+                },
+                {
+                    "id": "audit",
+                    "trigger_type": "kafka",
+                    "trigger_config": {"component": "audit", "topics": ["orders", "refunds"]}
+                }
+            ]
+        });
+        let locked_app = LockedApp::from_json(&serde_json::to_vec(&locked_app).unwrap()).unwrap();
+        App::new("test", locked_app)
+    }
+
+    fn new_trigger(app: &App) -> Result<KafkaTrigger> {
+        <KafkaTrigger as Trigger<TriggerFactors>>::new(ShutdownArgs::default(), app)
+    }
+
+    #[test]
+    fn consumers_from_metadata() {
+        let app = kafka_app(serde_json::json!({
+            "brokers": "localhost:9092",
+            "group_id": "default-group",
+            "options": {"security.protocol": "plaintext", "enable.auto.commit": "true"}
+        }));
+        let trigger = new_trigger(&app).unwrap();
+        let consumers = trigger
+            .consumers
+            .iter()
+            .map(|(id, _, config)| (id.as_str(), config))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            consumers["orders"].get("group.id"),
+            Some("orders-processor")
+        );
+        assert_eq!(consumers["audit"].get("group.id"), Some("default-group"));
+        for config in consumers.values() {
+            assert_eq!(config.get("bootstrap.servers"), Some("localhost:9092"));
+            assert_eq!(config.get("security.protocol"), Some("plaintext"));
+            assert_eq!(config.get("enable.auto.commit"), Some("false"));
+        }
+    }
+
+    #[test]
+    fn consumers_require_brokers_and_group() {
+        let app = kafka_app(serde_json::json!({"group_id": "default-group"}));
+        assert!(new_trigger(&app).is_err());
+        let app = kafka_app(serde_json::json!({"brokers": "localhost:9092"}));
+        assert!(new_trigger(&app).is_err());
+    }
+
+    fn message(offset: i64) -> OwnedMessage {
+        OwnedMessage::new(
+            Some(b"order".to_vec()),
+            None,
+            "orders".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            offset,
+            None,
+        )
+    }
+
+    #[test]
+    fn failed_messages_are_redelivered_with_backoff() {
+        let failed = || Err(anyhow::anyhow!("failed"));
+        let mut failures = 0;
+        assert_eq!(
+            settlement(&message(7), failed(), &mut failures),
+            Settlement::Redeliver(REDELIVERY_BACKOFF.delay(1))
+        );
+        assert_eq!(
+            settlement(&message(7), failed(), &mut failures),
+            Settlement::Redeliver(REDELIVERY_BACKOFF.delay(2))
+        );
+        assert_eq!(
+            settlement(&message(7), Ok(()), &mut failures),
+            Settlement::Commit
+        );
+        // The delay starts over once a message was handled
+        assert_eq!(
+            settlement(&message(8), failed(), &mut failures),
+            Settlement::Redeliver(REDELIVERY_BACKOFF.delay(1))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local Kafka broker"]
+    async fn failed_messages_are_redelivered_before_later_ones() {
+        use rdkafka::producer::{FutureProducer, FutureRecord};
+
+        let brokers = std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".into());
+        let topic = format!("spin-test-{}", std::process::id());
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .create()
+            .unwrap();
+        for payload in ["one", "two"] {
+            producer
+                .send(
+                    FutureRecord::<(), _>::to(&topic).payload(payload),
+                    Duration::from_secs(10),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &brokers)
+            .set("group.id", &topic)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.auto.commit", "false");
+        let topics = [topic];
+        let shutdown = Shutdown::default();
+        let mut seen = Vec::new();
+        // The handler fails for payloads seen for the first time
+        let payloads = collect_calls(4, |tx| {
+            consume(&client_config, &topics, &shutdown, move |payload| {
+                tx.send(payload.clone()).unwrap();
+                let first = !seen.contains(&payload);
+                seen.push(payload);
+                async move {
+                    anyhow::ensure!(!first, "failed");
+                    Ok(())
+                }
+            })
+        })
+        .await;
+        assert_eq!(
+            payloads,
+            [b"one", b"one", b"two", b"two"].map(|p| p.to_vec())
+        );
+    }
+}
@@ -1,15 +1,47 @@
 use containerd_shim::Config;
-use containerd_shim_wasm::{
-    container::Instance,
-    sandbox::cli::{revision, shim_main, version},
-};
+use containerd_shim_wasm::sandbox::cli::{revision, shim_main, version};
 
+#[cfg(feature = "amqp")]
+mod amqp_trigger;
+mod config;
 mod constants;
+#[cfg(feature = "cron")]
+mod cron_trigger;
 mod engine;
+mod exit_policy;
+#[cfg(feature = "http")]
+mod http_trigger;
+mod instance;
+#[cfg(feature = "kafka")]
+mod kafka_trigger;
+#[cfg(feature = "nats")]
+mod nats_trigger;
+mod plugin;
+mod precompile;
+mod registry;
 mod retain;
+mod runtime_config;
+mod shutdown;
 mod source;
+mod telemetry;
+#[cfg(feature = "http")]
+mod tls;
 mod trigger;
 mod utils;
+mod variables;
+
+#[cfg(not(any(
+    feature = "http",
+    feature = "redis",
+    feature = "sqs",
+    feature = "mqtt",
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+)))]
+compile_error!("at least one trigger feature must be enabled");
 
 fn main() {
     // Configure the shim to have only error level logging for performance improvements.
@@ -17,11 +49,5 @@ fn main() {
         default_log_level: "error".to_string(),
         ..Default::default()
     };
-    shim_main::<Instance<engine::SpinEngine>>(
-        "spin",
-        version!(),
-        revision!(),
-        "v2",
-        Some(shim_config),
-    );
+    shim_main::<instance::Instance>("spin", version!(), revision!(), "v2", Some(shim_config));
 }
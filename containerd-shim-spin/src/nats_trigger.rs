@@ -0,0 +1,494 @@
+//! This module contains the NATS trigger, which delivers messages from NATS subjects to components
+//!
+//! It is configured like the other message triggers, see
+//! [`handle_message`]. The connection is set with `url` and either a
+//! `credentials_file`, a `token` or a `user` with a `password`. Components are
+//! bound to core subjects, optionally as part of a queue group, or to
+//! JetStream durable consumers:
+//!
+//! ```toml
+//! [[trigger.nats]]
+//! component = "notifications"
+//! subject = "notifications.>"
+//! queue_group = "notifiers"
+//!
+//! [[trigger.nats]]
+//! component = "orders"
+//! subject = "orders.created"
+//! stream = "ORDERS"
+//! durable = "orders-processor"
+//! ```
+//!
+//! Core NATS has no redelivery, so messages the component failed to handle are
+//! lost. JetStream messages are acknowledged once the component handled them
+//! successfully. Otherwise, they are negatively acknowledged for redelivery
+//! after a delay, which grows with the number of times the message was
+//! delivered.
+
+use std::{future::Future, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, AckPolicy},
+        AckKind,
+    },
+    Client, ConnectOptions,
+};
+use bytes::Bytes;
+use futures::{future, StreamExt};
+use serde::Deserialize;
+use spin_app::App;
+use spin_factors::RuntimeFactors;
+use spin_trigger::{Trigger, TriggerApp};
+
+use crate::{
+    shutdown::{Shutdown, ShutdownArgs},
+    trigger::{handle_message, REDELIVERY_BACKOFF},
+};
+
+/// Application-level settings of the NATS trigger
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NatsTriggerMetadata {
+    /// Comma separated list of server URLs
+    url: Option<String>,
+    /// Path to a NATS credentials file
+    credentials_file: Option<PathBuf>,
+    token: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl NatsTriggerMetadata {
+    /// Checks that a server is configured, with complete credentials
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.url.is_some(),
+            "no NATS server configured: set `url` in the [trigger.nats] table of the runtime config"
+        );
+        anyhow::ensure!(
+            self.user.is_some() == self.password.is_some(),
+            "`user` and `password` must be set together in the [trigger.nats] table of the runtime config"
+        );
+        Ok(())
+    }
+}
+
+/// Configuration of a NATS trigger in the Spin manifest
+#[derive(Clone, Debug, Deserialize)]
+struct NatsTriggerConfig {
+    component: String,
+    subject: String,
+    /// Queue group of a core subscription
+    #[serde(default)]
+    queue_group: Option<String>,
+    /// Stream of a JetStream subscription
+    #[serde(default)]
+    stream: Option<String>,
+    /// Durable consumer of a JetStream subscription
+    #[serde(default)]
+    durable: Option<String>,
+}
+
+/// Where a NATS trigger receives its messages from
+#[derive(Clone, Debug, PartialEq)]
+enum Subscription {
+    Core {
+        subject: String,
+        queue_group: Option<String>,
+    },
+    JetStream {
+        stream: String,
+        durable: String,
+        subject: String,
+    },
+}
+
+impl TryFrom<&NatsTriggerConfig> for Subscription {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &NatsTriggerConfig) -> Result<Self> {
+        match (&config.stream, &config.durable) {
+            (None, None) => Ok(Self::Core {
+                subject: config.subject.clone(),
+                queue_group: config.queue_group.clone(),
+            }),
+            (Some(stream), Some(durable)) if config.queue_group.is_none() => Ok(Self::JetStream {
+                stream: stream.clone(),
+                durable: durable.clone(),
+                subject: config.subject.clone(),
+            }),
+            (Some(_), Some(_)) => Err(anyhow!(
+                "`queue_group` cannot be used with JetStream, use a shared `durable` consumer instead"
+            )),
+            _ => Err(anyhow!(
+                "`stream` and `durable` must be set together to consume from JetStream"
+            )),
+        }
+    }
+}
+
+pub(crate) struct NatsTrigger {
+    metadata: NatsTriggerMetadata,
+    subscriptions: Vec<(String, String, Subscription)>,
+    shutdown: Shutdown,
+}
+
+impl<F: RuntimeFactors> Trigger<F> for NatsTrigger {
+    const TYPE: &'static str = "nats";
+    type CliArgs = ShutdownArgs;
+    type InstanceState = ();
+
+    fn new(cli_args: Self::CliArgs, app: &App) -> Result<Self> {
+        let trigger_type = <Self as Trigger<F>>::TYPE;
+        let metadata = app
+            .get_trigger_metadata::<NatsTriggerMetadata>(trigger_type)?
+            .unwrap_or_default();
+        metadata.validate()?;
+        let subscriptions = app
+            .trigger_configs::<NatsTriggerConfig>(trigger_type)?
+            .into_iter()
+            .map(|(id, config)| {
+                let subscription = Subscription::try_from(&config)
+                    .with_context(|| format!("invalid configuration of NATS trigger {id:?}"))?;
+                Ok((id.to_string(), config.component, subscription))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            metadata,
+            subscriptions,
+            shutdown: cli_args.shutdown,
+        })
+    }
+
+    async fn run(self, trigger_app: TriggerApp<Self, F>) -> Result<()> {
+        let client = connect(&self.metadata).await?;
+        let trigger_app = &trigger_app;
+        let shutdown = &self.shutdown;
+        future::try_join_all(
+            self.subscriptions
+                .iter()
+                .map(|(id, component, subscription)| {
+                    let client = &client;
+                    async move {
+                        subscribe(client, subscription, shutdown, |payload| async move {
+                            handle_message(trigger_app, component, &payload)
+                                .await
+                                .with_context(|| {
+                                    format!("NATS trigger {id:?} failed to handle message")
+                                })
+                        })
+                        .await
+                        .with_context(|| format!("NATS trigger {id:?} failed"))
+                    }
+                }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Connects to the NATS servers configured for the application
+async fn connect(metadata: &NatsTriggerMetadata) -> Result<Client> {
+    let url = metadata.url.as_deref().unwrap_or_default();
+    let mut options = ConnectOptions::new();
+    if let Some(credentials_file) = &metadata.credentials_file {
+        options = options
+            .credentials_file(credentials_file)
+            .await
+            .with_context(|| format!("failed to load NATS credentials {credentials_file:?}"))?;
+    }
+    if let Some(token) = &metadata.token {
+        options = options.token(token.clone());
+    }
+    if let (Some(user), Some(password)) = (&metadata.user, &metadata.password) {
+        options = options.user_and_password(user.clone(), password.clone());
+    }
+    options
+        .connect(url)
+        .await
+        .with_context(|| format!("failed to connect to NATS server {url:?}"))
+}
+
+/// Passes each message of the subscription to the handler until the
+/// subscription ends or the shutdown starts.
+async fn subscribe<H, Fut>(
+    client: &Client,
+    subscription: &Subscription,
+    shutdown: &Shutdown,
+    mut handle: H,
+) -> Result<()>
+where
+    H: FnMut(Bytes) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    match subscription {
+        Subscription::Core {
+            subject,
+            queue_group,
+        } => {
+            let mut subscriber = match queue_group {
+                Some(queue_group) => {
+                    client
+                        .queue_subscribe(subject.clone(), queue_group.clone())
+                        .await?
+                }
+                None => client.subscribe(subject.clone()).await?,
+            };
+            log::info!(" >>> subscribed to NATS subject {subject:?}");
+            while let Some(message) = shutdown.unless_stopped(subscriber.next()).await.flatten() {
+                // Core NATS has no redelivery, so failures are only logged
+                if let Err(e) = handle(message.payload).await {
+                    log::error!(" >>> {e:?}");
+                }
+            }
+        }
+        Subscription::JetStream {
+            stream,
+            durable,
+            subject,
+        } => {
+            let consumer = jetstream::new(client.clone())
+                .get_stream(stream)
+                .await
+                .with_context(|| format!("failed to get JetStream stream {stream:?}"))?
+                .get_or_create_consumer(
+                    durable,
+                    pull::Config {
+                        durable_name: Some(durable.clone()),
+                        filter_subject: subject.clone(),
+                        ack_policy: AckPolicy::Explicit,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .with_context(|| format!("failed to get JetStream consumer {durable:?}"))?;
+            let mut messages = consumer.messages().await?;
+            log::info!(" >>> consuming JetStream stream {stream:?} as {durable:?}");
+            // Consecutive failures to receive a message
+            let mut failures = 0;
+            while let Some(received) = shutdown.unless_stopped(messages.next()).await.flatten() {
+                let message = match received {
+                    Ok(message) => message,
+                    Err(e) => {
+                        failures += 1;
+                        let delay = REDELIVERY_BACKOFF.delay(failures);
+                        log::error!(
+                            " >>> failed to receive JetStream message, retrying in {delay:?}: {e}"
+                        );
+                        if shutdown
+                            .unless_stopped(tokio::time::sleep(delay))
+                            .await
+                            .is_none()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                failures = 0;
+                let delivered = message.info().map_or(1, |info| info.delivered);
+                let ack = ack_kind(handle(message.payload.clone()).await, delivered);
+                message
+                    .ack_with(ack)
+                    .await
+                    .map_err(|e| anyhow!(e))
+                    .context("failed to acknowledge JetStream message")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How a JetStream message that was delivered the given number of times is
+/// acknowledged once the component handled it. A failed message is redelivered
+/// after a delay, which grows with the number of deliveries.
+fn ack_kind(handled: Result<()>, delivered: i64) -> AckKind {
+    match handled {
+        Ok(()) => AckKind::Ack,
+        Err(e) => {
+            let delay = REDELIVERY_BACKOFF.delay(u32::try_from(delivered).unwrap_or(u32::MAX));
+            log::error!(" >>> redelivering JetStream message in {delay:?}: {e:?}");
+            AckKind::Nak(Some(delay))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::collect_calls;
+
+    fn config(
+        stream: Option<&str>,
+        durable: Option<&str>,
+        queue_group: Option<&str>,
+    ) -> NatsTriggerConfig {
+        NatsTriggerConfig {
+            component: "orders".to_string(),
+            subject: "orders.created".to_string(),
+            queue_group: queue_group.map(Into::into),
+            stream: stream.map(Into::into),
+            durable: durable.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn metadata_requires_url_and_complete_credentials() {
+        let metadata = |user: Option<&str>, password: Option<&str>| NatsTriggerMetadata {
+            url: Some("nats://127.0.0.1:4222".to_string()),
+            user: user.map(Into::into),
+            password: password.map(Into::into),
+            ..Default::default()
+        };
+        assert!(metadata(None, None).validate().is_ok());
+        assert!(metadata(Some("spin"), Some("secret")).validate().is_ok());
+        let e = metadata(Some("spin"), None).validate().unwrap_err();
+        assert!(e.to_string().contains("`user` and `password`"), "{e}");
+        assert!(metadata(None, Some("secret")).validate().is_err());
+        assert!(NatsTriggerMetadata::default().validate().is_err());
+    }
+
+    #[test]
+    fn subscription_from_config() {
+        assert_eq!(
+            Subscription::try_from(&config(None, None, Some("workers"))).unwrap(),
+            Subscription::Core {
+                subject: "orders.created".to_string(),
+                queue_group: Some("workers".to_string()),
+            }
+        );
+        assert_eq!(
+            Subscription::try_from(&config(Some("ORDERS"), Some("processor"), None)).unwrap(),
+            Subscription::JetStream {
+                stream: "ORDERS".to_string(),
+                durable: "processor".to_string(),
+                subject: "orders.created".to_string(),
+            }
+        );
+        assert!(Subscription::try_from(&config(Some("ORDERS"), None, None)).is_err());
+        assert!(Subscription::try_from(&config(None, Some("processor"), None)).is_err());
+        assert!(Subscription::try_from(&config(
+            Some("ORDERS"),
+            Some("processor"),
+            Some("workers")
+        ))
+        .is_err());
+    }
+
+    /// Connects to the nats-server given by `NATS_URL`, which must have
+    /// JetStream enabled (`nats-server -js`)
+    async fn local_client() -> Client {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".into());
+        connect(&NatsTriggerMetadata {
+            url: Some(url),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Runs the subscription until the handler was called `count` times and
+    /// returns the payloads it was called with. The handler fails for payloads
+    /// seen for the first time if `fail_first` is set.
+    async fn collect_payloads(
+        client: &Client,
+        subscription: &Subscription,
+        count: usize,
+        fail_first: bool,
+        publish: impl Future<Output = ()>,
+    ) -> Vec<Bytes> {
+        let shutdown = Shutdown::default();
+        let mut seen = Vec::new();
+        collect_calls(count, |tx| {
+            let run = subscribe(client, subscription, &shutdown, move |payload| {
+                tx.send(payload.clone()).unwrap();
+                let first = !seen.contains(&payload);
+                seen.push(payload);
+                async move {
+                    if fail_first && first {
+                        anyhow::bail!("failed");
+                    }
+                    Ok(())
+                }
+            });
+            future::join(run, publish)
+        })
+        .await
+    }
+
+    #[test]
+    fn failed_jetstream_messages_are_redelivered_with_backoff() {
+        assert!(matches!(ack_kind(Ok(()), 1), AckKind::Ack));
+        let failed = || Err(anyhow!("failed"));
+        for (delivered, failures) in [(1, 1), (3, 3), (-1, u32::MAX), (i64::MAX, u32::MAX)] {
+            let AckKind::Nak(Some(delay)) = ack_kind(failed(), delivered) else {
+                panic!("message delivered {delivered} times is not redelivered");
+            };
+            assert_eq!(delay, REDELIVERY_BACKOFF.delay(failures));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local nats-server"]
+    async fn core_subscription_delivers_messages() {
+        let client = local_client().await;
+        let subscription = Subscription::Core {
+            subject: "spin.test.core".to_string(),
+            queue_group: Some("spin-test".to_string()),
+        };
+        let publish = async {
+            // Give the subscription time to be registered
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            for payload in ["one", "two"] {
+                client
+                    .publish("spin.test.core", Bytes::from(payload))
+                    .await
+                    .unwrap();
+            }
+        };
+        let payloads = collect_payloads(&client, &subscription, 2, false, publish).await;
+        assert_eq!(payloads, [Bytes::from("one"), Bytes::from("two")]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local nats-server"]
+    async fn jetstream_redelivers_failed_messages() {
+        let client = local_client().await;
+        let js = jetstream::new(client.clone());
+        let _ = js.delete_stream("SPIN_TEST").await;
+        js.create_stream(jetstream::stream::Config {
+            name: "SPIN_TEST".to_string(),
+            subjects: vec!["spin.test.jetstream".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        js.publish("spin.test.jetstream", Bytes::from("order"))
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let subscription = Subscription::JetStream {
+            stream: "SPIN_TEST".to_string(),
+            durable: "spin-test".to_string(),
+            subject: "spin.test.jetstream".to_string(),
+        };
+        // The message is redelivered after the handler failed and acknowledged afterwards
+        let payloads = collect_payloads(&client, &subscription, 2, true, async {}).await;
+        assert_eq!(payloads, [Bytes::from("order"), Bytes::from("order")]);
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let mut consumer = js
+            .get_stream("SPIN_TEST")
+            .await
+            .unwrap()
+            .get_consumer::<pull::Config>("spin-test")
+            .await
+            .unwrap();
+        assert_eq!(consumer.info().await.unwrap().num_ack_pending, 0);
+        js.delete_stream("SPIN_TEST").await.unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+//! This module contains the logic for running Spin trigger plugins that are not built into the shim
+//!
+//! A trigger plugin is an executable named `trigger-<type>` in one of the
+//! directories listed in `SPIN_TRIGGER_PLUGIN_PATH` of the shim. Plugins are
+//! installed on the node, found when the shim starts, and mounted into the
+//! containers at the same path, for example with a `hostPath` volume. The
+//! container cannot choose the directories. By default, a plugin only runs if
+//! the file in the container is the file of the node, so that images cannot
+//! ship executables the node did not install; `SPIN_TRUST_IMAGE_TRIGGER_PLUGINS`
+//! lifts that restriction.
+//!
+//! The plugin runs as a child process, started the way `spin up` starts it:
+//! the locked app is handed over through `SPIN_LOCKED_URL`, the runtime config
+//! file and state directory as arguments, and the application variables
+//! provided by container environment variables as `SPIN_VARIABLE_*`
+//! environment variables of the plugin. Variables provided by the secrets
+//! directory are not copied into the environment of the plugin; the plugin is
+//! pointed at the directory through `SPIN_VARIABLES_SECRETS_DIR` instead.
+//!
+//! The trigger exits when the child process exits. When the shim is stopped,
+//! the child process is sent SIGTERM and drained like other in-flight work,
+//! before it is killed at the end of the drain period.
+//!
+//! Plugins load components themselves, so they cannot run components that were
+//! precompiled by the shim. Such apps fail to start rather than handing the
+//! plugin components it cannot load.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        process::ExitStatusExt,
+    },
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::{self, Either, LocalBoxFuture};
+use log::info;
+use tokio::process::{Child, Command};
+use url::Url;
+
+use crate::{
+    constants::{
+        SPIN_TRIGGER_PLUGIN_PATH_ENV, SPIN_TRIGGER_WORKING_DIR,
+        SPIN_TRUST_IMAGE_TRIGGER_PLUGINS_ENV, SPIN_VARIABLES_SECRETS_DIR_ENV,
+    },
+    registry::{TriggerContext, TriggerRunner},
+    shutdown::Shutdown,
+    source::Source,
+    trigger::TriggerFuture,
+    utils::is_env_flag_set,
+};
+
+/// Prefix of the executable name of trigger plugins
+const PLUGIN_PREFIX: &str = "trigger-";
+
+/// Identifies a file by its device and inode
+type FileId = (u64, u64);
+
+/// The trigger plugins of the node, found when the shim starts
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TriggerPlugins {
+    /// Directories searched for plugins, in order
+    dirs: Vec<PathBuf>,
+    /// The plugins of the node, by path
+    node: BTreeMap<PathBuf, FileId>,
+    /// Whether plugins that are not the plugins of the node may run
+    trust_image_plugins: bool,
+}
+
+impl TriggerPlugins {
+    /// Finds the trigger plugins in the directories listed in
+    /// `SPIN_TRIGGER_PLUGIN_PATH`. Must be called by the shim process, so that
+    /// the plugins are controlled by the node rather than by the container spec.
+    pub(crate) fn from_env() -> Result<Self> {
+        let dirs = env::var_os(SPIN_TRIGGER_PLUGIN_PATH_ENV)
+            .map(|path| env::split_paths(&path).collect())
+            .unwrap_or_default();
+        let trust_image_plugins = is_env_flag_set(SPIN_TRUST_IMAGE_TRIGGER_PLUGINS_ENV);
+        Self::find(dirs, trust_image_plugins)
+    }
+
+    fn find(dirs: Vec<PathBuf>, trust_image_plugins: bool) -> Result<Self> {
+        let node = find_plugins(&dirs, |_| true)?
+            .into_values()
+            .filter_map(|path| {
+                let id = file_id(&path)?;
+                Some((path, id))
+            })
+            .collect();
+        Ok(Self {
+            dirs,
+            node,
+            trust_image_plugins,
+        })
+    }
+
+    /// Returns the trigger plugins that may run in the container, by trigger
+    /// type. Unless plugins of images are trusted, only the files of the node
+    /// mounted at the same path are returned.
+    pub(crate) fn resolve(&self) -> Result<BTreeMap<String, PathBuf>> {
+        if env::var_os(SPIN_TRIGGER_PLUGIN_PATH_ENV).is_some() {
+            log::warn!(
+                " >>> ignoring {SPIN_TRIGGER_PLUGIN_PATH_ENV} of the container: trigger plugins are configured on the shim"
+            );
+        }
+        find_plugins(&self.dirs, |path| {
+            if self
+                .node
+                .get(path)
+                .is_some_and(|id| file_id(path) == Some(*id))
+            {
+                return true;
+            }
+            if self.trust_image_plugins {
+                log::warn!(" >>> running trigger plugin {path:?} of the image");
+                return true;
+            }
+            log::warn!(
+                " >>> refusing trigger plugin {path:?}, which is not installed on the node; set {SPIN_TRUST_IMAGE_TRIGGER_PLUGINS_ENV}=true on the shim to run trigger plugins of images"
+            );
+            false
+        })
+    }
+}
+
+fn file_id(path: &Path) -> Option<FileId> {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// Finds the trigger plugins in the given directories that are accepted. Like
+/// executables on the `PATH`, plugins in earlier directories take precedence.
+fn find_plugins(
+    dirs: &[PathBuf],
+    accept: impl Fn(&Path) -> bool,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut plugins = BTreeMap::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warn!(" >>> trigger plugin directory {dir:?} does not exist");
+                continue;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to read trigger plugin directory {dir:?}"))
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(trigger_type) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(PLUGIN_PREFIX))
+                .filter(|trigger_type| !trigger_type.is_empty())
+            else {
+                continue;
+            };
+            let path = entry.path();
+            if !plugins.contains_key(trigger_type) && is_executable(&path) && accept(&path) {
+                plugins.insert(trigger_type.to_string(), path);
+            }
+        }
+    }
+    Ok(plugins)
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Runs a trigger type with a trigger plugin
+pub(crate) struct PluginRunner {
+    pub(crate) trigger_type: String,
+    pub(crate) executable: PathBuf,
+}
+
+impl PluginRunner {
+    /// Returns an error if a component of the trigger type was precompiled by
+    /// the shim, as the plugin would fail to load it
+    fn check_components(&self, ctx: &TriggerContext) -> Result<()> {
+        // Only components of OCI applications are precompiled
+        if !matches!(ctx.app_source, Source::Oci(_)) {
+            return Ok(());
+        }
+        let engine = wasmtime::Engine::default();
+        let app = ctx.app();
+        for trigger in app.triggers_with_type(&self.trigger_type) {
+            let component = trigger.component()?;
+            let Some(path) = component
+                .locked
+                .source
+                .content
+                .source
+                .as_deref()
+                .and_then(|source| Url::parse(source).ok()?.to_file_path().ok())
+            else {
+                continue;
+            };
+            if engine.detect_precompiled_file(&path)?.is_some() {
+                bail!(
+                    "{} trigger plugin cannot run component {:?}, which was precompiled by the shim; run the app from an image that is not precompiled",
+                    self.trigger_type,
+                    component.id()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the plugin, handing it the locked app and runtime config
+    fn spawn(&self, ctx: &TriggerContext) -> Result<PluginProcess> {
+        let state_dir = &ctx.config.state_dir;
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("failed to create state directory {state_dir:?}"))?;
+        let locked_app_file = state_dir.join(format!("{PLUGIN_PREFIX}{}.lock", self.trigger_type));
+        fs::write(&locked_app_file, serde_json::to_vec(ctx.locked_app)?)
+            .with_context(|| format!("failed to write locked app to {locked_app_file:?}"))?;
+        let locked_url = Url::from_file_path(&locked_app_file)
+            .map_err(|()| anyhow!("invalid locked app path {locked_app_file:?}"))?;
+
+        let mut command = Command::new(&self.executable);
+        command
+            .arg("--state-dir")
+            .arg(state_dir)
+            .env("SPIN_LOCKED_URL", locked_url.as_str())
+            .env("SPIN_WORKING_DIR", SPIN_TRIGGER_WORKING_DIR)
+            .envs(ctx.config.variables.provider_env(&ctx.locked_app.variables))
+            // Kills the plugin if it did not exit by the end of the drain period
+            .kill_on_drop(true);
+        if let Some(secrets_dir) = ctx.config.variables.secrets_dir() {
+            command.env(SPIN_VARIABLES_SECRETS_DIR_ENV, secrets_dir);
+        }
+        if let Some(runtime_config_file) = &ctx.config.runtime_config_file {
+            command
+                .arg("--runtime-config-file")
+                .arg(runtime_config_file);
+        }
+        let child = command
+            .spawn()
+            .with_context(|| format!("failed to start trigger plugin {:?}", self.executable))?;
+        Ok(PluginProcess {
+            trigger_type: self.trigger_type.clone(),
+            child,
+        })
+    }
+}
+
+impl TriggerRunner for PluginRunner {
+    fn start<'a>(&'a self, ctx: TriggerContext<'a>) -> LocalBoxFuture<'a, Result<TriggerFuture>> {
+        Box::pin(async move {
+            info!(
+                " >>> running {} trigger with plugin {:?}",
+                self.trigger_type, self.executable
+            );
+            self.check_components(&ctx)?;
+            let mut process = self.spawn(&ctx)?;
+            let shutdown = ctx.shutdown.clone();
+            Ok(Box::pin(async move { process.wait(&shutdown).await }) as TriggerFuture)
+        })
+    }
+}
+
+/// A running trigger plugin
+struct PluginProcess {
+    trigger_type: String,
+    child: Child,
+}
+
+impl PluginProcess {
+    /// Waits for the plugin to exit, returning an error if it did not exit
+    /// successfully. Once the shutdown starts, the plugin is sent SIGTERM and
+    /// waited for, so that it can drain its in-flight work.
+    async fn wait(&mut self, shutdown: &Shutdown) -> Result<()> {
+        let exited =
+            match future::select(Box::pin(self.child.wait()), Box::pin(shutdown.stopped())).await {
+                Either::Left((status, _)) => Some(status?),
+                Either::Right(((), _)) => None,
+            };
+        let status = match exited {
+            Some(status) => status,
+            None => {
+                self.terminate();
+                self.child.wait().await?
+            }
+        };
+        exit_result(&self.trigger_type, status)
+    }
+
+    fn terminate(&self) {
+        // The ID is only unset once the child was reaped, so it cannot refer to another process
+        if let Some(pid) = self.child.id() {
+            info!(
+                " >>> forwarding termination to {} trigger plugin",
+                self.trigger_type
+            );
+            // SAFETY: kill has no memory safety requirements
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+}
+
+/// Maps the exit status of a trigger plugin to the result of the trigger
+fn exit_result(trigger_type: &str, status: ExitStatus) -> Result<()> {
+    match (status.code(), status.signal()) {
+        (Some(0), _) => {
+            info!(" >>> {trigger_type} trigger plugin exited");
+            Ok(())
+        }
+        (Some(code), _) => bail!("{trigger_type} trigger plugin exited with code {code}"),
+        (None, Some(signal)) => {
+            bail!("{trigger_type} trigger plugin was killed by signal {signal}")
+        }
+        (None, None) => bail!("{trigger_type} trigger plugin exited with {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_executable(dir: &Path, name: &str, mode: u32) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn finds_executable_plugins() {
+        let image = tempfile::tempdir().unwrap();
+        let node = tempfile::tempdir().unwrap();
+        let image_kinesis = write_executable(image.path(), "trigger-kinesis", 0o755);
+        write_executable(image.path(), "trigger-pubsub", 0o644);
+        write_executable(image.path(), "trigger-", 0o755);
+        write_executable(image.path(), "kinesis", 0o755);
+        write_executable(node.path(), "trigger-kinesis", 0o755);
+        let node_pubsub = write_executable(node.path(), "trigger-pubsub", 0o755);
+
+        let plugins = find_plugins(
+            &[
+                image.path().to_path_buf(),
+                image.path().join("missing"),
+                node.path().to_path_buf(),
+            ],
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(
+            plugins,
+            BTreeMap::from([
+                ("kinesis".to_string(), image_kinesis),
+                ("pubsub".to_string(), node_pubsub),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_plugins_of_the_node_are_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let kinesis = write_executable(dir.path(), "trigger-kinesis", 0o755);
+        let pubsub = write_executable(dir.path(), "trigger-pubsub", 0o755);
+        let plugins = TriggerPlugins::find(vec![dir.path().to_path_buf()], false).unwrap();
+        // Files in the container that the node did not find are refused, even
+        // at the path of a plugin of the node
+        let image_pubsub = write_executable(dir.path(), "image-pubsub", 0o755);
+        fs::rename(image_pubsub, pubsub).unwrap();
+        write_executable(dir.path(), "trigger-sqs", 0o755);
+        assert_eq!(
+            plugins.resolve().unwrap(),
+            BTreeMap::from([("kinesis".to_string(), kinesis.clone())])
+        );
+
+        let plugins = TriggerPlugins {
+            trust_image_plugins: true,
+            ..plugins
+        };
+        assert_eq!(
+            plugins.resolve().unwrap().into_keys().collect::<Vec<_>>(),
+            ["kinesis", "pubsub", "sqs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn plugin_exit_status_is_the_trigger_result() {
+        for (script, expected) in [
+            ("exit 0", None),
+            ("exit 3", Some("kinesis trigger plugin exited with code 3")),
+            (
+                "kill -TERM $$",
+                Some("kinesis trigger plugin was killed by signal 15"),
+            ),
+        ] {
+            let child = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(script)
+                .spawn()
+                .unwrap();
+            let mut process = PluginProcess {
+                trigger_type: "kinesis".to_string(),
+                child,
+            };
+            let result = process.wait(&Shutdown::default()).await;
+            assert_eq!(result.err().map(|e| e.to_string()).as_deref(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn plugin_finishes_in_flight_work_on_shutdown() {
+        // The plugin finishes the invocation in flight when it is terminated
+        let dir = tempfile::tempdir().unwrap();
+        let child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(r#"trap 'sleep 0.2; touch "$1/drained"; exit 0' TERM; touch "$1/ready"; while :; do sleep 0.01; done"#)
+            .arg("sh")
+            .arg(dir.path())
+            .spawn()
+            .unwrap();
+        let mut process = PluginProcess {
+            trigger_type: "kinesis".to_string(),
+            child,
+        };
+        let shutdown = Shutdown::default();
+        let mut waiting = std::pin::pin!(process.wait(&shutdown));
+        while !dir.path().join("ready").exists() {
+            assert!(futures::poll!(waiting.as_mut()).is_pending());
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        shutdown.stop();
+        waiting.await.unwrap();
+        assert!(dir.path().join("drained").exists());
+    }
+}
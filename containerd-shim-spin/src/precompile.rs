@@ -0,0 +1,201 @@
+//! This module contains the logic for sealing components precompiled by the shim
+//!
+//! Precompiled components are native code, so the shim only loads precompiled
+//! artifacts that carry a seal created with a node-local key. Sealing is done
+//! during `precompile` and the seal is checked before a layer is handed to the
+//! component loader.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::constants;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Marks the end of a sealed artifact
+const SEAL_MAGIC: &[u8; 8] = b"SPINSEAL";
+/// Length of the HMAC-SHA256 tag stored in the seal
+const SEAL_TAG_LEN: usize = 32;
+/// Length of a newly generated precompile key
+const KEY_LEN: usize = 32;
+
+/// Node-local key used to seal components precompiled by the shim
+#[derive(Clone)]
+pub(crate) struct PrecompileKey {
+    key: Vec<u8>,
+}
+
+impl PrecompileKey {
+    pub(crate) fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Loads the key from the location configured by the
+    /// `SPIN_PRECOMPILE_KEY_PATH` environment variable of the shim, creating
+    /// a new random key if none exists yet.
+    pub(crate) fn from_env() -> Result<Self> {
+        let path = env::var(constants::SPIN_PRECOMPILE_KEY_PATH_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(constants::SPIN_PRECOMPILE_KEY_PATH_DEFAULT));
+        Self::load_or_create(&path)
+    }
+
+    fn load_or_create(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(key) => {
+                ensure!(
+                    key.len() >= KEY_LEN,
+                    "precompile key {path:?} must be at least {KEY_LEN} bytes long"
+                );
+                Ok(Self::new(key))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => match Self::create(path)? {
+                Some(key) => Ok(key),
+                // Another shim created the key in the meantime
+                None => Self::load_or_create(path),
+            },
+            Err(e) => Err(e).with_context(|| format!("failed to read precompile key {path:?}")),
+        }
+    }
+
+    /// Creates a new random key at the given path, or returns `None` if
+    /// another shim created it in the meantime.
+    ///
+    /// The key is written to a temporary file that is then linked into place,
+    /// so that concurrent shims never read a partially written key.
+    fn create(path: &Path) -> Result<Option<Self>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {parent:?}"))?;
+        }
+        let mut key = vec![0u8; KEY_LEN];
+        getrandom::getrandom(&mut key).context("failed to generate precompile key")?;
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix).context("failed to generate precompile key")?;
+        let tmp = path.with_extension(format!("{:016x}.tmp", u64::from_ne_bytes(suffix)));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("failed to create precompile key {tmp:?}"))?;
+        let created = file
+            .write_all(&key)
+            .and_then(|()| file.sync_all())
+            .and_then(|()| fs::hard_link(&tmp, path));
+        let _ = fs::remove_file(&tmp);
+        match created {
+            Ok(()) => {
+                log::info!("created precompile key at {:?}", path);
+                Ok(Some(Self::new(key)))
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to create precompile key {path:?}")),
+        }
+    }
+
+    /// Returns a non-secret identifier of the key, so that rotating the key
+    /// invalidates artifacts sealed with the previous one.
+    pub(crate) fn id(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"containerd-shim-spin precompile key id");
+        hasher.update(&self.key);
+        hasher.finalize().to_vec()
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    /// Appends a seal to the given precompiled artifact
+    pub(crate) fn seal(&self, mut artifact: Vec<u8>) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(&artifact);
+        let tag = mac.finalize().into_bytes();
+        artifact.extend_from_slice(&tag);
+        artifact.extend_from_slice(SEAL_MAGIC);
+        artifact
+    }
+
+    /// Returns the artifact without its seal if the content carries one.
+    ///
+    /// Returns `Ok(None)` if the content is not sealed and an error if the seal
+    /// does not match the content.
+    pub(crate) fn unseal<'a>(&self, content: &'a [u8]) -> Result<Option<&'a [u8]>> {
+        let Some(rest) = content.strip_suffix(SEAL_MAGIC) else {
+            return Ok(None);
+        };
+        if rest.len() < SEAL_TAG_LEN {
+            return Ok(None);
+        }
+        let (artifact, tag) = rest.split_at(rest.len() - SEAL_TAG_LEN);
+        let mut mac = self.mac();
+        mac.update(artifact);
+        if mac.verify_slice(tag).is_err() {
+            bail!("precompiled artifact was not sealed by this node");
+        }
+        Ok(Some(artifact))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip() {
+        let key = PrecompileKey::new([1; KEY_LEN]);
+        let sealed = key.seal(b"artifact".to_vec());
+        assert_eq!(key.unseal(&sealed).unwrap(), Some(&b"artifact"[..]));
+        assert_eq!(key.unseal(b"artifact").unwrap(), None);
+    }
+
+    #[test]
+    fn unseal_rejects_foreign_or_tampered_seal() {
+        let key = PrecompileKey::new([1; KEY_LEN]);
+        let other = PrecompileKey::new([2; KEY_LEN]);
+        let sealed = other.seal(b"artifact".to_vec());
+        assert!(key.unseal(&sealed).is_err());
+
+        let mut tampered = key.seal(b"artifact".to_vec());
+        tampered[0] = b'A';
+        assert!(key.unseal(&tampered).is_err());
+    }
+
+    #[test]
+    fn load_or_create_persists_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("precompile.key");
+        let created = PrecompileKey::load_or_create(&path).unwrap();
+        let loaded = PrecompileKey::load_or_create(&path).unwrap();
+        assert_eq!(created.id(), loaded.id());
+        assert_eq!(fs::read(&path).unwrap().len(), KEY_LEN);
+    }
+
+    #[test]
+    fn load_or_create_concurrently_agrees_on_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("precompile.key");
+        let ids = std::thread::scope(|scope| {
+            let shims = (0..8)
+                .map(|_| scope.spawn(|| PrecompileKey::load_or_create(&path).unwrap().id()))
+                .collect::<Vec<_>>();
+            shims
+                .into_iter()
+                .map(|shim| shim.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        // Only the key is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
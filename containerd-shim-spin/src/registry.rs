@@ -0,0 +1,425 @@
+//! This module contains the registry of the triggers the shim can run
+//!
+//! Each trigger registers its type, how its CLI args are built and how it is
+//! started. Adding a trigger to the shim only requires registering it in
+//! [`TriggerRegistry::builtin`]. Trigger types that are not built into the shim
+//! may be run by trigger plugins, see [`crate::plugin`].
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use futures::future::LocalBoxFuture;
+use spin_app::{locked::LockedApp, App};
+use spin_runtime_factors::TriggerFactors;
+#[cfg(any(feature = "redis", feature = "sqs"))]
+use spin_trigger::cli::NoCliArgs;
+use spin_trigger::{loader::ComponentLoader, Trigger};
+
+#[cfg(any(
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+use crate::shutdown::ShutdownArgs;
+use crate::{
+    config::AppConfig, plugin::PluginRunner, shutdown::Shutdown, source::Source,
+    trigger::TriggerFuture,
+};
+
+/// Everything a trigger is started with
+pub(crate) struct TriggerContext<'a> {
+    /// ID of the application, which is unique to the container
+    pub(crate) app_id: &'a str,
+    pub(crate) locked_app: &'a LockedApp,
+    pub(crate) app_source: &'a Source,
+    pub(crate) loader: &'a ComponentLoader,
+    pub(crate) config: &'a AppConfig,
+    /// Arguments the container was started with
+    #[cfg(feature = "command")]
+    pub(crate) args: &'a [String],
+    /// Tells the trigger to stop taking on new work
+    pub(crate) shutdown: &'a Shutdown,
+}
+
+impl TriggerContext<'_> {
+    /// Returns the [`App`] the trigger runs
+    pub(crate) fn app(&self) -> App {
+        App::new(self.app_id, self.locked_app.clone())
+    }
+}
+
+/// Starts a trigger, returning the future that runs it until it exits
+pub(crate) trait TriggerRunner: Send + Sync {
+    fn start<'a>(&'a self, ctx: TriggerContext<'a>) -> LocalBoxFuture<'a, Result<TriggerFuture>>;
+}
+
+/// Builds the CLI args of a Spin trigger
+#[cfg(any(
+    feature = "redis",
+    feature = "mqtt",
+    feature = "sqs",
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+pub(crate) type CliArgsBuilder<T> =
+    fn(&TriggerContext) -> Result<<T as Trigger<TriggerFactors>>::CliArgs>;
+
+/// Maps the result of a trigger to the exit code of the container
+pub(crate) type ExitCode = fn(Result<()>) -> Result<i32>;
+
+/// Runs a Spin trigger with the CLI args produced by its builder
+#[cfg(any(
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+struct SpinTriggerRunner<T: Trigger<TriggerFactors>> {
+    cli_args: CliArgsBuilder<T>,
+}
+
+#[cfg(any(
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+impl<T: Trigger<TriggerFactors> + 'static> TriggerRunner for SpinTriggerRunner<T> {
+    fn start<'a>(&'a self, ctx: TriggerContext<'a>) -> LocalBoxFuture<'a, Result<TriggerFuture>> {
+        Box::pin(async move {
+            let cli_args = (self.cli_args)(&ctx)?;
+            crate::trigger::run::<T>(cli_args, ctx.app(), ctx.loader, ctx.config).await
+        })
+    }
+}
+
+/// Runs a Spin trigger that cannot be told to stop taking on new work until it
+/// drained after the shutdown started, see [`crate::trigger::run_until_shutdown`]
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+struct UntilShutdownRunner<T: Trigger<TriggerFactors>> {
+    cli_args: CliArgsBuilder<T>,
+}
+
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+impl<T: Trigger<TriggerFactors> + 'static> TriggerRunner for UntilShutdownRunner<T> {
+    fn start<'a>(&'a self, ctx: TriggerContext<'a>) -> LocalBoxFuture<'a, Result<TriggerFuture>> {
+        Box::pin(async move {
+            let cli_args = (self.cli_args)(&ctx)?;
+            crate::trigger::run_until_shutdown::<T>(
+                cli_args,
+                ctx.app(),
+                ctx.loader,
+                ctx.config,
+                ctx.shutdown,
+            )
+            .await
+        })
+    }
+}
+
+/// A trigger type known to the registry
+pub(crate) struct Registration {
+    runner: Box<dyn TriggerRunner>,
+    exit_code: ExitCode,
+    restartable: bool,
+}
+
+impl Registration {
+    /// Sets how the result of the trigger maps to the exit code of the
+    /// container. By default, the container exits with 0 if the trigger
+    /// exited successfully.
+    #[cfg(feature = "command")]
+    pub(crate) fn exit_code(&mut self, exit_code: ExitCode) -> &mut Self {
+        self.exit_code = exit_code;
+        self
+    }
+
+    /// Keeps the trigger from being restarted by the `restart` exit policy
+    /// when it fails, for triggers that run to completion.
+    #[cfg(any(feature = "command", test))]
+    pub(crate) fn run_once(&mut self) -> &mut Self {
+        self.restartable = false;
+        self
+    }
+}
+
+/// The trigger types the shim can run
+#[derive(Default)]
+pub(crate) struct TriggerRegistry {
+    triggers: BTreeMap<String, Registration>,
+}
+
+impl TriggerRegistry {
+    /// The triggers built into the shim, as selected by its cargo features
+    pub(crate) fn builtin() -> Self {
+        let mut registry = Self::default();
+        #[cfg(feature = "http")]
+        registry.register_runner(
+            <spin_trigger_http::HttpTrigger as Trigger<TriggerFactors>>::TYPE,
+            crate::http_trigger::HttpTriggerRunner,
+        );
+        #[cfg(feature = "redis")]
+        registry.register_until_shutdown::<spin_trigger_redis::RedisTrigger>(|_| Ok(NoCliArgs));
+        #[cfg(feature = "sqs")]
+        registry.register_until_shutdown::<trigger_sqs::SqsTrigger>(|_| Ok(NoCliArgs));
+        #[cfg(feature = "command")]
+        registry
+            .register::<trigger_command::CommandTrigger>(|ctx| {
+                Ok(trigger_command::CliArgs {
+                    guest_args: ctx.args.to_vec(),
+                })
+            })
+            .exit_code(crate::trigger::command_exit_code)
+            .run_once();
+        #[cfg(feature = "mqtt")]
+        registry.register_until_shutdown::<trigger_mqtt::MqttTrigger>(|_| {
+            Ok(trigger_mqtt::CliArgs { test: false })
+        });
+        #[cfg(feature = "cron")]
+        registry.register::<crate::cron_trigger::CronTrigger>(|ctx| {
+            Ok(ShutdownArgs::new(ctx.shutdown))
+        });
+        #[cfg(feature = "kafka")]
+        registry.register::<crate::kafka_trigger::KafkaTrigger>(|ctx| {
+            Ok(ShutdownArgs::new(ctx.shutdown))
+        });
+        #[cfg(feature = "nats")]
+        registry.register::<crate::nats_trigger::NatsTrigger>(|ctx| {
+            Ok(ShutdownArgs::new(ctx.shutdown))
+        });
+        #[cfg(feature = "amqp")]
+        registry.register::<crate::amqp_trigger::AmqpTrigger>(|ctx| {
+            Ok(ShutdownArgs::new(ctx.shutdown))
+        });
+        registry
+    }
+
+    /// Registers a Spin trigger under its type, replacing any trigger
+    /// registered for the same type. The trigger either runs to completion or
+    /// stops taking on new work and drains itself when the shutdown starts.
+    #[cfg(any(
+        feature = "command",
+        feature = "cron",
+        feature = "kafka",
+        feature = "nats",
+        feature = "amqp"
+    ))]
+    pub(crate) fn register<T>(&mut self, cli_args: CliArgsBuilder<T>) -> &mut Registration
+    where
+        T: Trigger<TriggerFactors> + 'static,
+    {
+        self.register_runner(
+            <T as Trigger<TriggerFactors>>::TYPE,
+            SpinTriggerRunner::<T> { cli_args },
+        )
+    }
+
+    /// Registers a Spin trigger that cannot be told to stop taking on new work
+    /// under its type, replacing any trigger registered for the same type. The
+    /// shim stops the trigger once it drained after the shutdown started.
+    #[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+    pub(crate) fn register_until_shutdown<T>(
+        &mut self,
+        cli_args: CliArgsBuilder<T>,
+    ) -> &mut Registration
+    where
+        T: Trigger<TriggerFactors> + 'static,
+    {
+        self.register_runner(
+            <T as Trigger<TriggerFactors>>::TYPE,
+            UntilShutdownRunner::<T> { cli_args },
+        )
+    }
+
+    /// Registers a trigger type that is started by a custom runner, replacing
+    /// any trigger registered for the same type.
+    pub(crate) fn register_runner(
+        &mut self,
+        trigger_type: impl Into<String>,
+        runner: impl TriggerRunner + 'static,
+    ) -> &mut Registration {
+        let registration = Registration {
+            runner: Box::new(runner),
+            exit_code: |result| result.map(|()| 0),
+            restartable: true,
+        };
+        let trigger_type = trigger_type.into();
+        self.triggers.insert(trigger_type.clone(), registration);
+        self.triggers.get_mut(&trigger_type).unwrap()
+    }
+
+    /// Registers the given trigger plugins, by trigger type. Plugins for
+    /// trigger types that are already registered are ignored.
+    pub(crate) fn register_plugins(&mut self, plugins: BTreeMap<String, PathBuf>) {
+        for (trigger_type, executable) in plugins {
+            if self.triggers.contains_key(&trigger_type) {
+                log::info!(
+                    " >>> {trigger_type} trigger is built into the shim, ignoring plugin {executable:?}"
+                );
+                continue;
+            }
+            let runner = PluginRunner {
+                trigger_type: trigger_type.clone(),
+                executable,
+            };
+            self.register_runner(trigger_type, runner);
+        }
+    }
+
+    /// The registered trigger types
+    pub(crate) fn trigger_types(&self) -> HashSet<&str> {
+        self.triggers.keys().map(String::as_str).collect()
+    }
+
+    /// Returns the trigger types of the app, or an error naming the first
+    /// trigger type that is not registered.
+    pub(crate) fn app_trigger_types(&self, locked_app: &LockedApp) -> Result<HashSet<String>> {
+        locked_app
+            .triggers
+            .iter()
+            .map(|trigger| {
+                let trigger_type = &trigger.trigger_type;
+                anyhow::ensure!(
+                    self.triggers.contains_key(trigger_type),
+                    "Only {} triggers are currently supported. Found unsupported trigger: {:?}",
+                    self.triggers.keys().cloned().collect::<Vec<_>>().join(", "),
+                    trigger_type
+                );
+                Ok(trigger_type.clone())
+            })
+            .collect()
+    }
+
+    /// Starts the trigger of the given type
+    pub(crate) async fn start(
+        &self,
+        trigger_type: &str,
+        ctx: TriggerContext<'_>,
+    ) -> Result<TriggerFuture> {
+        self.registration(trigger_type)?.runner.start(ctx).await
+    }
+
+    /// Maps the result of the trigger of the given type to the exit code of the container
+    pub(crate) fn exit_code(&self, trigger_type: &str, result: Result<()>) -> Result<i32> {
+        (self.registration(trigger_type)?.exit_code)(result)
+    }
+
+    /// Whether the trigger of the given type may be restarted when it fails
+    pub(crate) fn restartable(&self, trigger_type: &str) -> bool {
+        self.registration(trigger_type)
+            .is_ok_and(|registration| registration.restartable)
+    }
+
+    fn registration(&self, trigger_type: &str) -> Result<&Registration> {
+        self.triggers
+            .get(trigger_type)
+            .with_context(|| format!("no trigger registered for type {trigger_type:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trigger that exits as soon as it is started
+    struct ExitingRunner;
+
+    impl TriggerRunner for ExitingRunner {
+        fn start<'a>(
+            &'a self,
+            _ctx: TriggerContext<'a>,
+        ) -> LocalBoxFuture<'a, Result<TriggerFuture>> {
+            Box::pin(async { Ok(Box::pin(async { anyhow::bail!("exited") }) as TriggerFuture) })
+        }
+    }
+
+    fn locked_app(trigger_type: &str) -> LockedApp {
+        let app_json = serde_json::json!({
+            "spin_lock_version": 1,
+            "components": [],
+            "triggers": [{"id": "t", "trigger_type": trigger_type, "trigger_config": {}}]
+        });
+        LockedApp::from_json(&serde_json::to_vec(&app_json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unsupported_trigger_error_lists_registered_triggers() {
+        let registry = TriggerRegistry::builtin();
+        let err = registry
+            .app_trigger_types(&locked_app("fancy"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("Found unsupported trigger: \"fancy\""));
+        for trigger_type in registry.trigger_types() {
+            assert!(err.contains(trigger_type));
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_runner_is_started() {
+        let mut registry = TriggerRegistry::default();
+        registry
+            .register_runner("fancy", ExitingRunner)
+            .exit_code(|result| Ok(if result.is_ok() { 0 } else { 7 }));
+        let locked_app = locked_app("fancy");
+        assert_eq!(
+            registry.app_trigger_types(&locked_app).unwrap(),
+            HashSet::from(["fancy".to_string()])
+        );
+
+        let ctx = TriggerContext {
+            app_id: "test",
+            locked_app: &locked_app,
+            app_source: &Source::File(PathBuf::from("spin.toml")),
+            loader: &ComponentLoader::default(),
+            config: &AppConfig::default(),
+            #[cfg(feature = "command")]
+            args: &[],
+            shutdown: &Shutdown::default(),
+        };
+        let running = registry.start("fancy", ctx).await.unwrap();
+        assert_eq!(registry.exit_code("fancy", running.await).unwrap(), 7);
+        assert!(registry.exit_code("other", Ok(())).is_err());
+    }
+
+    #[test]
+    fn triggers_that_run_once_are_not_restartable() {
+        let mut registry = TriggerRegistry::default();
+        registry.register_runner("fancy", ExitingRunner);
+        registry
+            .register_runner("oneshot", ExitingRunner)
+            .run_once();
+        assert!(registry.restartable("fancy"));
+        assert!(!registry.restartable("oneshot"));
+    }
+
+    #[test]
+    fn plugins_do_not_replace_registered_triggers() {
+        let mut registry = TriggerRegistry::default();
+        registry
+            .register_runner("fancy", ExitingRunner)
+            .exit_code(|_| Ok(7));
+        registry.register_plugins(BTreeMap::from([
+            ("fancy".to_string(), PathBuf::from("/plugins/trigger-fancy")),
+            (
+                "kinesis".to_string(),
+                PathBuf::from("/plugins/trigger-kinesis"),
+            ),
+        ]));
+        assert_eq!(
+            registry.trigger_types(),
+            HashSet::from(["fancy", "kinesis"])
+        );
+        assert_eq!(registry.exit_code("fancy", Ok(())).unwrap(), 7);
+        assert_eq!(registry.exit_code("kinesis", Ok(())).unwrap(), 0);
+    }
+}
@@ -3,16 +3,123 @@
 use std::collections::HashSet;
 
 use anyhow::{bail, Context, Result};
-use spin_app::locked::LockedApp;
+use spin_app::locked::{LockedApp, LockedMap, Variable};
 use spin_factor_outbound_networking::{allowed_outbound_hosts, parse_service_chaining_target};
 
+use crate::{
+    runtime_config::TRIGGERS_METADATA_KEY,
+    variables::{
+        collect_value_template_references, component_variable_references, VariablesConfig,
+    },
+};
+
+/// Returns the IDs of the components selected by the given patterns: those
+/// matching any of the `retained` patterns, or all components if there are
+/// none, and none of the `excluded` patterns.
+///
+/// In patterns, `*` matches any sequence of characters and `?` matches any
+/// single character. Patterns that match no component of the app are errors.
+pub fn select_components(
+    locked_app: &LockedApp,
+    retained: Option<&[String]>,
+    excluded: &[String],
+) -> Result<Vec<String>> {
+    let component_ids = locked_app
+        .components
+        .iter()
+        .map(|c| c.id.as_str())
+        .collect::<Vec<_>>();
+    let unmatched = retained
+        .unwrap_or_default()
+        .iter()
+        .chain(excluded)
+        .filter(|pattern| !component_ids.iter().any(|id| matches_pattern(pattern, id)))
+        .map(|pattern| format!("{pattern:?}"))
+        .collect::<Vec<_>>();
+    if !unmatched.is_empty() {
+        bail!(
+            "Specified component patterns did not match any component in application: {}",
+            unmatched.join(", ")
+        );
+    }
+    let selected = component_ids
+        .into_iter()
+        .filter(|id| match retained {
+            Some(retained) => retained.iter().any(|p| matches_pattern(p, id)),
+            None => true,
+        })
+        .filter(|id| !excluded.iter().any(|p| matches_pattern(p, id)))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if selected.is_empty() {
+        bail!("Specified component patterns do not select any component in application");
+    }
+    Ok(selected)
+}
+
+/// Returns whether the component ID matches the pattern, in which `*` matches
+/// any sequence of characters and `?` matches any single character
+fn matches_pattern(pattern: &str, id: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let id = id.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // Position after the last `*` in the pattern and the position in the ID it matched up to
+    let mut star = None;
+    while i < id.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, i));
+            }
+            Some(&c) if c == '?' || c == id[i] => {
+                p += 1;
+                i += 1;
+            }
+            // Let the last `*` match one more character
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 /// Scrubs the locked app to only contain the given list of components
 /// Introspects the LockedApp to find and selectively retain the triggers that correspond to those components
-pub fn retain_components(locked_app: &mut LockedApp, retained_components: &[String]) -> Result<()> {
+pub fn retain_components(
+    locked_app: &mut LockedApp,
+    retained_components: &[String],
+    variables: &VariablesConfig,
+) -> Result<()> {
+    retain_and_prune(locked_app, |locked_app| {
+        retain_component_ids(locked_app, retained_components, "not selected", variables)
+    })
+}
+
+// Retains the given components, along with the triggers bound to them. The
+// other components are dropped for the given reason, such as "not selected",
+// which errors name when retained components depend on dropped ones.
+fn retain_component_ids(
+    locked_app: &mut LockedApp,
+    retained_components: &[String],
+    dropped: &str,
+    variables: &VariablesConfig,
+) -> Result<()> {
     // Create a temporary app to access parsed component and trigger information
     let tmp_app = spin_app::App::new("tmp", locked_app.clone());
     validate_retained_components_exist(&tmp_app, retained_components)?;
-    validate_retained_components_service_chaining(&tmp_app, retained_components)?;
+    validate_retained_components_service_chaining(
+        &tmp_app,
+        retained_components,
+        dropped,
+        &locked_app.variables,
+        variables,
+    )?;
     let (component_ids, trigger_ids): (HashSet<String>, HashSet<String>) = tmp_app
         .triggers()
         .filter_map(|t| match t.component() {
@@ -29,17 +136,197 @@ pub fn retain_components(locked_app: &mut LockedApp, retained_components: &[Stri
     Ok(())
 }
 
+/// Scrubs the locked app to only contain the triggers of the given types, along
+/// with the components bound to them
+pub fn retain_triggers(
+    locked_app: &mut LockedApp,
+    retained_trigger_types: &[String],
+    variables: &VariablesConfig,
+) -> Result<()> {
+    // Create a temporary app to access parsed component and trigger information
+    let tmp_app = spin_app::App::new("tmp", locked_app.clone());
+    validate_retained_trigger_types_exist(&tmp_app, retained_trigger_types)?;
+    let retained_components = tmp_app
+        .triggers()
+        .filter(|t| retained_trigger_types.iter().any(|r| r == t.trigger_type()))
+        .filter_map(|t| Some(t.component().ok()?.id().to_string()))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    retain_and_prune(locked_app, |locked_app| {
+        retain_component_ids(
+            locked_app,
+            &retained_components,
+            "bound only to triggers not selected to run",
+            variables,
+        )
+        .with_context(|| {
+            format!(
+                "failed to select the {} triggers",
+                retained_trigger_types.join(", ")
+            )
+        })?;
+        // Components may also be bound to triggers of other types
+        locked_app
+            .triggers
+            .retain(|t| retained_trigger_types.contains(&t.trigger_type));
+        Ok(())
+    })
+}
+
+/// Scrubs the locked app of the triggers whose type is not in the given set of
+/// supported trigger types, along with the components bound only to them
+pub fn retain_supported_triggers(
+    locked_app: &mut LockedApp,
+    supported_triggers: &HashSet<&str>,
+    variables: &VariablesConfig,
+) -> Result<()> {
+    // Create a temporary app to access parsed component and trigger information
+    let tmp_app = spin_app::App::new("tmp", locked_app.clone());
+    let mut retained_components = Vec::new();
+    let mut unsupported_triggers = Vec::new();
+    for t in tmp_app.triggers() {
+        let Ok(component) = t.component() else {
+            continue;
+        };
+        if supported_triggers.contains(t.trigger_type()) {
+            retained_components.push(component.id().to_string());
+        } else {
+            unsupported_triggers.push((t.trigger_type().to_string(), component.id().to_string()));
+        }
+    }
+    if unsupported_triggers.is_empty() {
+        return Ok(());
+    }
+    if retained_components.is_empty() {
+        bail!("Application does not contain any supported triggers");
+    }
+    for (trigger_type, component) in unsupported_triggers {
+        if retained_components.contains(&component) {
+            log::warn!("Skipping unsupported {trigger_type:?} trigger of component {component:?}");
+        } else {
+            log::warn!(
+                "Dropping component {component:?} bound to unsupported {trigger_type:?} trigger"
+            );
+        }
+    }
+    retain_and_prune(locked_app, |locked_app| {
+        retain_component_ids(
+            locked_app,
+            &retained_components,
+            "bound only to unsupported triggers",
+            variables,
+        )
+        .context("failed to drop components bound to unsupported triggers")?;
+        // Components may also be bound to supported triggers
+        locked_app
+            .triggers
+            .retain(|t| supported_triggers.contains(t.trigger_type.as_str()));
+        Ok(())
+    })
+}
+
+// Scrubs the locked app with the given function, then prunes the variables and
+// trigger settings of the app that the retained components and triggers no
+// longer reference, and logs a summary of what was removed.
+fn retain_and_prune(
+    locked_app: &mut LockedApp,
+    retain: impl FnOnce(&mut LockedApp) -> Result<()>,
+) -> Result<()> {
+    let components = locked_app
+        .components
+        .iter()
+        .map(|c| c.id.clone())
+        .collect::<Vec<_>>();
+    let trigger_count = locked_app.triggers.len();
+    retain(locked_app)?;
+
+    let removed_components = components
+        .into_iter()
+        .filter(|id| !locked_app.components.iter().any(|c| &c.id == id))
+        .collect::<Vec<_>>();
+    let removed_triggers = trigger_count - locked_app.triggers.len();
+    // Settings of removed trigger types may reference variables
+    let removed_trigger_settings = prune_trigger_settings(locked_app);
+    let removed_variables = prune_variables(locked_app);
+    if !removed_components.is_empty() || removed_triggers > 0 {
+        log::info!(
+            " >>> selective deployment removed components [{}], {removed_triggers} triggers, variables [{}] and settings of trigger types [{}]",
+            removed_components.join(", "),
+            removed_variables.join(", "),
+            removed_trigger_settings.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// Removes the variables that are not referenced by the config, allowed
+// outbound hosts or other metadata of the components, nor by the triggers.
+// Returns the names of the removed variables.
+fn prune_variables(locked_app: &mut LockedApp) -> Vec<String> {
+    let mut referenced = HashSet::new();
+    for component in &locked_app.components {
+        referenced.extend(component_variable_references(component));
+    }
+    for trigger in &locked_app.triggers {
+        collect_value_template_references(&trigger.trigger_config, &mut referenced);
+    }
+    if let Some(settings) = locked_app.metadata.get(TRIGGERS_METADATA_KEY) {
+        collect_value_template_references(settings, &mut referenced);
+    }
+    let mut removed = Vec::new();
+    locked_app.variables.retain(|name, _| {
+        let keep = referenced.contains(name);
+        if !keep {
+            removed.push(name.clone());
+        }
+        keep
+    });
+    removed
+}
+
+// Removes the application-level settings of trigger types the app no longer
+// has triggers of. Returns the removed trigger types.
+fn prune_trigger_settings(locked_app: &mut LockedApp) -> Vec<String> {
+    let trigger_types = locked_app
+        .triggers
+        .iter()
+        .map(|t| t.trigger_type.as_str())
+        .collect::<HashSet<_>>();
+    let Some(settings) = locked_app
+        .metadata
+        .get_mut(TRIGGERS_METADATA_KEY)
+        .and_then(|settings| settings.as_object_mut())
+    else {
+        return Vec::new();
+    };
+    let mut removed = Vec::new();
+    settings.retain(|trigger_type, _| {
+        let keep = trigger_types.contains(trigger_type.as_str());
+        if !keep {
+            removed.push(trigger_type.clone());
+        }
+        keep
+    });
+    removed
+}
+
 // Validates that all service chaining of an app will be satisfied by the
 // retained components.
 //
 // This does a best effort look up of components that are
 // allowed to be accessed through service chaining and will error early if a
 // component is configured to to chain to another component that is not
-// retained. All wildcard service chaining is disallowed and all templated URLs
-// are ignored.
+// retained. All wildcard service chaining is disallowed. Templated URLs are
+// resolved against the app variables and the container environment, and
+// ignored if a variable has no value. Errors name the reason the other
+// components are dropped.
 fn validate_retained_components_service_chaining(
     app: &spin_app::App,
     retained_components: &[String],
+    dropped: &str,
+    app_variables: &LockedMap<Variable>,
+    variables: &VariablesConfig,
 ) -> Result<()> {
     app
         .triggers().try_for_each(|t| {
@@ -47,16 +334,22 @@ fn validate_retained_components_service_chaining(
             if retained_components.contains(&component.id().to_string()) {
             let allowed_hosts = allowed_outbound_hosts(&component).context("failed to get allowed hosts")?;
             for host in allowed_hosts {
-                // Templated URLs are not yet resolved at this point, so ignore unresolvable URIs
+                let host = match resolve_host_template(&host, app_variables, variables) {
+                    Ok(host) => host,
+                    Err(variable) => {
+                        log::warn!("Cannot validate service chaining of component {:?} to {host:?}: variable {variable:?} has no value", component.id());
+                        continue;
+                    }
+                };
                 if let Ok(uri) = host.parse::<http::Uri>() {
                     if let Some(chaining_target) = parse_service_chaining_target(&uri) {
                         if !retained_components.contains(&chaining_target) {
                             if chaining_target == "*" {
-                                bail!("Component selected with '--component {}' cannot use wildcard service chaining: allowed_outbound_hosts = [\"http://*.spin.internal\"]", component.id());
+                                bail!("Component {:?} cannot use wildcard service chaining, as components {dropped} are dropped: allowed_outbound_hosts = [\"http://*.spin.internal\"]", component.id());
                             }
                             bail!(
-                                "Component selected with '--component {}' cannot use service chaining to unselected component: allowed_outbound_hosts = [\"http://{}.spin.internal\"]",
-                                component.id(), chaining_target
+                                "Component {:?} cannot use service chaining to component {chaining_target:?}, which is dropped as it is {dropped}: allowed_outbound_hosts = [\"http://{chaining_target}.spin.internal\"]",
+                                component.id()
                             );
                         }
                     }
@@ -69,6 +362,33 @@ fn validate_retained_components_service_chaining(
     Ok(())
 }
 
+// Resolves the `{{ variable }}` references of a templated allowed outbound host.
+// Returns the name of the first variable without a value if the host cannot be
+// resolved.
+fn resolve_host_template(
+    host: &str,
+    app_variables: &LockedMap<Variable>,
+    variables: &VariablesConfig,
+) -> std::result::Result<String, String> {
+    let mut resolved = String::new();
+    let mut rest = host;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + len].trim();
+        let value = app_variables
+            .get(name)
+            .and_then(|variable| variables.value(name, variable))
+            .ok_or_else(|| name.to_string())?;
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&value);
+        rest = &rest[start + len + 2..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
 // Validates that all components specified to be retained actually exist in the app
 fn validate_retained_components_exist(
     app: &spin_app::App,
@@ -86,6 +406,23 @@ fn validate_retained_components_exist(
     Ok(())
 }
 
+// Validates that the app has triggers of all trigger types specified to be retained
+fn validate_retained_trigger_types_exist(
+    app: &spin_app::App,
+    retained_trigger_types: &[String],
+) -> Result<()> {
+    let app_trigger_types = app
+        .triggers()
+        .map(|t| t.trigger_type().to_string())
+        .collect::<HashSet<_>>();
+    for t in retained_trigger_types {
+        if !app_trigger_types.contains(t) {
+            bail!("Specified trigger type \"{t}\" not found in application");
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -115,7 +452,7 @@ mod test {
             source = "does-not-exist.wasm"
         };
         let mut locked_app = build_locked_app(&manifest).await.unwrap();
-        retain_components(&mut locked_app, &["empty".to_string()]).unwrap();
+        retain_components(&mut locked_app, &["empty".to_string()], &Default::default()).unwrap();
         let components = locked_app
             .components
             .iter()
@@ -140,14 +477,17 @@ mod test {
             source = "does-not-exist.wasm"
         };
         let mut locked_app = build_locked_app(&manifest).await.unwrap();
-        let Err(e) = retain_components(&mut locked_app, &["dne".to_string()]) else {
+        let Err(e) = retain_components(&mut locked_app, &["dne".to_string()], &Default::default())
+        else {
             panic!("Expected component not found error");
         };
         assert_eq!(
             e.to_string(),
             "Specified component \"dne\" not found in application"
         );
-        assert!(retain_components(&mut locked_app, &["dne".to_string()]).is_err());
+        assert!(
+            retain_components(&mut locked_app, &["dne".to_string()], &Default::default()).is_err()
+        );
     }
 
     #[tokio::test]
@@ -181,28 +521,36 @@ mod test {
         let mut locked_app = build_locked_app(&manifest)
             .await
             .expect("could not build locked app");
-        let Err(e) = retain_components(&mut locked_app, &["empty".to_string()]) else {
+        let Err(e) =
+            retain_components(&mut locked_app, &["empty".to_string()], &Default::default())
+        else {
             panic!("Expected service chaining to non-retained component error");
         };
         assert_eq!(
             e.to_string(),
-            "Component selected with '--component empty' cannot use service chaining to unselected component: allowed_outbound_hosts = [\"http://another.spin.internal\"]"
+            "Component \"empty\" cannot use service chaining to component \"another\", which is dropped as it is not selected: allowed_outbound_hosts = [\"http://another.spin.internal\"]"
         );
         let Err(e) = retain_components(
             &mut locked_app,
             &["third".to_string(), "another".to_string()],
+            &Default::default(),
         ) else {
             panic!("Expected wildcard service chaining error");
         };
         assert_eq!(
             e.to_string(),
-            "Component selected with '--component third' cannot use wildcard service chaining: allowed_outbound_hosts = [\"http://*.spin.internal\"]"
+            "Component \"third\" cannot use wildcard service chaining, as components not selected are dropped: allowed_outbound_hosts = [\"http://*.spin.internal\"]"
         );
-        assert!(retain_components(&mut locked_app, &["another".to_string()]).is_ok());
+        assert!(retain_components(
+            &mut locked_app,
+            &["another".to_string()],
+            &Default::default()
+        )
+        .is_ok());
     }
 
     #[tokio::test]
-    async fn test_retain_components_app_with_templated_host_passes() {
+    async fn test_retain_components_app_with_templated_host_is_resolved() {
         let manifest = toml::toml! {
             spin_manifest_version = 2
 
@@ -210,7 +558,8 @@ mod test {
             name = "test-app"
 
             [variables]
-            host = { default = "test" }
+            host = { default = "another" }
+            unset = { required = true }
 
             [[trigger.test-trigger]]
             component = "empty"
@@ -223,6 +572,7 @@ mod test {
 
             [component.another]
             source = "does-not-exist.wasm"
+            allowed_outbound_hosts = ["http://{{ unset }}.spin.internal"]
 
             [[trigger.third-trigger]]
             component = "third"
@@ -231,11 +581,364 @@ mod test {
             source = "does-not-exist.wasm"
             allowed_outbound_hosts = ["http://{{ host }}.spin.internal"]
         };
-        let mut locked_app = build_locked_app(&manifest)
+        let locked_app = build_locked_app(&manifest)
             .await
             .expect("could not build locked app");
+        let Err(e) = retain_components(
+            &mut locked_app.clone(),
+            &["empty".to_string(), "third".to_string()],
+            &Default::default(),
+        ) else {
+            panic!("Expected service chaining to non-retained component error");
+        };
+        assert_eq!(
+            e.to_string(),
+            "Component \"third\" cannot use service chaining to component \"another\", which is dropped as it is not selected: allowed_outbound_hosts = [\"http://another.spin.internal\"]"
+        );
+        // Hosts with variables without a value cannot be validated
+        assert!(retain_components(
+            &mut locked_app.clone(),
+            &["another".to_string()],
+            &Default::default()
+        )
+        .is_ok());
+        let variables = VariablesConfig::new([("HOST".to_string(), "empty".to_string())]).unwrap();
+        assert!(retain_components(
+            &mut locked_app.clone(),
+            &["empty".to_string(), "third".to_string()],
+            &variables
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_resolve_host_template() {
+        let app_variables = serde_json::from_value::<LockedMap<Variable>>(serde_json::json!({
+            "service": {"default": "users", "secret": false},
+            "port": {"secret": false}
+        }))
+        .unwrap();
+        let variables =
+            VariablesConfig::new([("SPIN_VARIABLE_PORT".to_string(), "8080".to_string())]).unwrap();
+        assert_eq!(
+            resolve_host_template(
+                "http://{{ service }}.spin.internal:{{port}}",
+                &app_variables,
+                &variables
+            ),
+            Ok("http://users.spin.internal:8080".to_string())
+        );
+        assert_eq!(
+            resolve_host_template(
+                "http://{{ other }}.spin.internal",
+                &app_variables,
+                &variables
+            ),
+            Err("other".to_string())
+        );
+        assert_eq!(
+            resolve_host_template("https://example.com", &app_variables, &variables),
+            Ok("https://example.com".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retain_supported_triggers_drops_unsupported_components() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.http]]
+            route = "/"
+            component = "web"
+
+            [[trigger.cron]]
+            cron_expression = "0 * * * * *"
+            component = "web"
+
+            [[trigger.cron]]
+            cron_expression = "0 * * * * *"
+            component = "job"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+
+            [component.job]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        retain_supported_triggers(
+            &mut locked_app,
+            &HashSet::from(["http"]),
+            &Default::default(),
+        )
+        .unwrap();
+        let components = locked_app
+            .components
+            .iter()
+            .map(|c| c.id.to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(components, HashSet::from(["web".to_string()]));
+        assert_eq!(locked_app.triggers.len(), 1);
+        assert_eq!(locked_app.triggers[0].trigger_type, "http");
+    }
+
+    #[tokio::test]
+    async fn test_retain_supported_triggers_fails_without_supported_triggers() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.cron]]
+            cron_expression = "0 * * * * *"
+            component = "job"
+
+            [component.job]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        assert!(retain_supported_triggers(
+            &mut locked_app,
+            &HashSet::from(["http"]),
+            &Default::default()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retain_triggers_filtering_for_trigger_type_works() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.http]]
+            route = "/"
+            component = "web"
+
+            [[trigger.redis]]
+            channel = "messages"
+            component = "web"
+
+            [[trigger.redis]]
+            channel = "messages"
+            component = "worker"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+
+            [component.worker]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        retain_triggers(&mut locked_app, &["http".to_string()], &Default::default()).unwrap();
+        let components = locked_app
+            .components
+            .iter()
+            .map(|c| c.id.to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(components, HashSet::from(["web".to_string()]));
+        assert_eq!(locked_app.triggers.len(), 1);
+        assert_eq!(locked_app.triggers[0].trigger_type, "http");
+    }
+
+    #[tokio::test]
+    async fn test_retain_triggers_filtering_for_non_existent_trigger_type_fails() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.http]]
+            route = "/"
+            component = "web"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        let Err(e) = retain_triggers(&mut locked_app, &["redis".to_string()], &Default::default())
+        else {
+            panic!("Expected trigger type not found error");
+        };
+        assert_eq!(
+            e.to_string(),
+            "Specified trigger type \"redis\" not found in application"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retain_triggers_app_with_service_chaining_fails() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.http]]
+            route = "/"
+            component = "web"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+            allowed_outbound_hosts = ["http://worker.spin.internal"]
+
+            [[trigger.redis]]
+            channel = "messages"
+            component = "worker"
+
+            [component.worker]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        let Err(e) = retain_triggers(&mut locked_app, &["http".to_string()], &Default::default())
+        else {
+            panic!("Expected service chaining to non-retained component error");
+        };
+        assert_eq!(e.to_string(), "failed to select the http triggers");
+        assert_eq!(
+            e.root_cause().to_string(),
+            "Component \"web\" cannot use service chaining to component \"worker\", which is dropped as it is bound only to triggers not selected to run: allowed_outbound_hosts = [\"http://worker.spin.internal\"]"
+        );
         assert!(
-            retain_components(&mut locked_app, &["empty".to_string(), "third".to_string()]).is_ok()
+            retain_triggers(&mut locked_app, &["redis".to_string()], &Default::default()).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("api", "api"));
+        assert!(!matches_pattern("api", "api-users"));
+        assert!(matches_pattern("api-*", "api-users"));
+        assert!(matches_pattern("api-*", "api-"));
+        assert!(!matches_pattern("api-*", "web-api"));
+        assert!(matches_pattern("*-api", "users-api"));
+        assert!(matches_pattern("*api*", "users-api-v2"));
+        assert!(matches_pattern("api-v?", "api-v2"));
+        assert!(!matches_pattern("api-v?", "api-v10"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("a*b*c", "aXbYbZ"));
+        assert!(matches_pattern("*", "anything"));
+    }
+
+    #[tokio::test]
+    async fn test_select_components_with_patterns() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [[trigger.test-trigger]]
+            component = "api-users"
+
+            [[trigger.test-trigger]]
+            component = "api-orders"
+
+            [[trigger.test-trigger]]
+            component = "api-legacy"
+
+            [[trigger.test-trigger]]
+            component = "web"
+
+            [component.api-users]
+            source = "does-not-exist.wasm"
+
+            [component.api-orders]
+            source = "does-not-exist.wasm"
+
+            [component.api-legacy]
+            source = "does-not-exist.wasm"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+        };
+        let locked_app = build_locked_app(&manifest).await.unwrap();
+        let select = |retained: Option<&[&str]>, excluded: &[&str]| {
+            let retained = retained.map(|r| r.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+            let excluded = excluded.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            select_components(&locked_app, retained.as_deref(), &excluded).map(|mut selected| {
+                selected.sort();
+                selected
+            })
+        };
+
+        assert_eq!(
+            select(Some(&["api-*"]), &["*-legacy"]).unwrap(),
+            ["api-orders", "api-users"]
+        );
+        assert_eq!(select(None, &["api-*"]).unwrap(), ["web"]);
+        assert_eq!(
+            select(Some(&["web", "api-users"]), &[]).unwrap(),
+            ["api-users", "web"]
+        );
+        assert_eq!(
+            select(Some(&["api-*", "worker-*"]), &["old"]).unwrap_err().to_string(),
+            "Specified component patterns did not match any component in application: \"worker-*\", \"old\""
+        );
+        assert!(select(Some(&["api-*"]), &["*"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retain_components_prunes_unused_variables_and_trigger_settings() {
+        let manifest = toml::toml! {
+            spin_manifest_version = 2
+
+            [application]
+            name = "test-app"
+
+            [application.trigger.redis]
+            address = "redis://{{ redis_host }}"
+
+            [variables]
+            api_token = { required = true }
+            backend_host = { default = "backend.example.com" }
+            redis_host = { default = "localhost" }
+            unused = { default = "unused" }
+
+            [[trigger.http]]
+            route = "/"
+            component = "web"
+
+            [[trigger.redis]]
+            channel = "messages"
+            component = "worker"
+
+            [component.web]
+            source = "does-not-exist.wasm"
+            allowed_outbound_hosts = ["https://{{ backend_host }}"]
+
+            [component.web.variables]
+            token = "Bearer {{ api_token }}"
+
+            [component.worker]
+            source = "does-not-exist.wasm"
+        };
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        retain_components(&mut locked_app, &["web".to_string()], &Default::default()).unwrap();
+        assert_eq!(
+            locked_app.variables.keys().collect::<Vec<_>>(),
+            ["api_token", "backend_host"]
+        );
+        assert!(locked_app.metadata[TRIGGERS_METADATA_KEY]
+            .get("redis")
+            .is_none());
+
+        let mut locked_app = build_locked_app(&manifest).await.unwrap();
+        retain_triggers(&mut locked_app, &["redis".to_string()], &Default::default()).unwrap();
+        assert_eq!(
+            locked_app.variables.keys().collect::<Vec<_>>(),
+            ["redis_host"]
+        );
+        assert_eq!(
+            locked_app.metadata[TRIGGERS_METADATA_KEY]["redis"]["address"],
+            "redis://{{ redis_host }}"
         );
     }
 }
@@ -0,0 +1,696 @@
+//! This module contains the logic for locating and layering the runtime config of a Spin application
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    os::fd::{FromRawFd, IntoRawFd},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use spin_app::locked::LockedApp;
+use toml::{Table, Value};
+
+use crate::{config::AppConfig, constants};
+
+/// Locked app metadata holding the application-level settings of each trigger type
+pub(crate) const TRIGGERS_METADATA_KEY: &str = "triggers";
+/// Runtime config table holding the settings of the triggers run by the shim.
+/// It is not passed on to Spin, which rejects unknown runtime config keys.
+const TRIGGER_SETTINGS_KEY: &str = "trigger";
+/// Runtime config arrays of the providers of the application variables, under
+/// the current name and the name older Spin versions use
+const VARIABLES_PROVIDERS_KEYS: [&str; 2] = ["variables_provider", "config_provider"];
+
+/// The runtime config of a Spin application
+#[derive(Debug, Default)]
+pub(crate) struct RuntimeConfig {
+    /// The runtime config file the triggers should be configured with, if any
+    pub(crate) file: Option<PathBuf>,
+    /// Settings of the triggers, keyed by trigger type
+    trigger_settings: Table,
+    /// Whether the runtime config configures providers of the application variables
+    variables_providers: bool,
+}
+
+impl RuntimeConfig {
+    /// Whether application variables may be provided by the providers
+    /// configured in the runtime config, such as Vault
+    pub(crate) fn configures_variables_providers(&self) -> bool {
+        self.variables_providers
+    }
+
+    /// Adds the trigger settings from the runtime config to the trigger
+    /// metadata of the locked app, overriding settings from the manifest.
+    pub(crate) fn apply_trigger_settings(&self, locked_app: &mut LockedApp) -> Result<()> {
+        if self.trigger_settings.is_empty() {
+            return Ok(());
+        }
+        let triggers = locked_app
+            .metadata
+            .entry(TRIGGERS_METADATA_KEY)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()))
+            .as_object_mut()
+            .context("trigger metadata of the application must be a table")?;
+        for (trigger_type, settings) in &self.trigger_settings {
+            let serde_json::Value::Object(settings) = serde_json::to_value(settings)? else {
+                bail!("runtime config `{TRIGGER_SETTINGS_KEY}.{trigger_type}` must be a table");
+            };
+            let metadata = triggers
+                .entry(trigger_type)
+                .or_insert_with(|| serde_json::Value::Object(Default::default()))
+                .as_object_mut()
+                .with_context(|| {
+                    format!("metadata of {trigger_type:?} triggers must be a table")
+                })?;
+            metadata.extend(settings);
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the runtime config of the application.
+///
+/// The runtime config layers are listed in the `SPIN_RUNTIME_CONFIG_PATHS`
+/// environment variable, as read into the [`AppConfig`]. If it is not set, the
+/// runtime config is loaded from the default location if one exists. If
+/// `SPIN_RUNTIME_CONFIG_EXPAND_ENV` is set, `${VAR}` references to container environment variables are expanded
+/// in each layer. Multiple layers are merged in order, and the result is kept
+/// in memory whenever it differs from the single configured file, so that
+/// expanded secrets are never written to disk. Relative paths in the merged
+/// config are resolved against the directory of the layer that set them, as
+/// they would be if the layer was used as is.
+pub(crate) fn resolve_runtime_config(config: &AppConfig) -> Result<RuntimeConfig> {
+    let paths = match &config.runtime_config_paths {
+        Some(paths) => {
+            for path in paths {
+                if !path.exists() {
+                    bail!(
+                        "runtime config {path:?} listed in {} does not exist",
+                        constants::SPIN_RUNTIME_CONFIG_PATHS_ENV
+                    );
+                }
+            }
+            paths.clone()
+        }
+        None => {
+            let default_path = Path::new(constants::RUNTIME_CONFIG_PATH);
+            if !default_path.exists() {
+                return Ok(RuntimeConfig::default());
+            }
+            vec![default_path.into()]
+        }
+    };
+    if paths.is_empty() {
+        return Ok(RuntimeConfig::default());
+    }
+
+    let expand = config.expand_runtime_config;
+    let mut layers = Vec::with_capacity(paths.len());
+    let mut expanded_layers = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let layer = load_layer(path)?;
+        let expanded = if expand {
+            expand_env_table(layer.clone(), path, "")?
+        } else {
+            layer.clone()
+        };
+        expanded_layers.push((path.as_path(), expanded));
+        layers.push((path.as_path(), layer));
+    }
+    if let [(path, layer)] = layers.as_slice() {
+        if *layer == expanded_layers[0].1 && !layer.contains_key(TRIGGER_SETTINGS_KEY) {
+            return Ok(RuntimeConfig {
+                file: Some(path.to_path_buf()),
+                trigger_settings: Table::new(),
+                variables_providers: has_variables_providers(layer),
+            });
+        }
+    }
+
+    // Log the configuration before expansion so that secrets are not written to the logs
+    let merged = merge_layers(layers)?;
+    log::debug!(
+        "<<< merged runtime config from {:?}:\n{}",
+        paths,
+        toml::to_string(&merged).unwrap_or_default()
+    );
+    let expanded_layers = expanded_layers
+        .into_iter()
+        .map(|(path, layer)| {
+            let dir = path.parent().unwrap_or(Path::new("/"));
+            (path, resolve_relative_paths(layer, dir))
+        })
+        .collect();
+    let mut merged = merge_layers(expanded_layers)?;
+    let trigger_settings = match merged.remove(TRIGGER_SETTINGS_KEY) {
+        Some(Value::Table(settings)) => settings,
+        Some(_) => bail!("runtime config `{TRIGGER_SETTINGS_KEY}` must be a table"),
+        None => Table::new(),
+    };
+    let variables_providers = has_variables_providers(&merged);
+    let merged = toml::to_string(&merged).context("failed to serialize runtime config")?;
+    let path = write_in_memory(&merged).context("failed to write merged runtime config")?;
+    Ok(RuntimeConfig {
+        file: Some(path),
+        trigger_settings,
+        variables_providers,
+    })
+}
+
+/// Whether the runtime config configures providers of the application variables
+fn has_variables_providers(config: &Table) -> bool {
+    VARIABLES_PROVIDERS_KEYS
+        .iter()
+        .any(|key| config.contains_key(*key))
+}
+
+/// Writes the runtime config to an anonymous file in memory, returning a path
+/// it can be opened at for as long as the shim runs. The file is not closed on
+/// exec, so trigger plugins started by the shim open it at the same path.
+fn write_in_memory(content: &str) -> io::Result<PathBuf> {
+    // SAFETY: the name is a valid C string
+    let fd = unsafe { libc::memfd_create(c"runtime-config.toml".as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just created and is owned by nothing else
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(content.as_bytes())?;
+    Ok(PathBuf::from(format!(
+        "/proc/self/fd/{}",
+        file.into_raw_fd()
+    )))
+}
+
+fn load_layer(path: &Path) -> Result<Table> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read runtime config {path:?}"))?;
+    content
+        .parse::<Table>()
+        .with_context(|| format!("failed to parse runtime config {path:?}"))
+}
+
+/// Expands `${VAR}` references in the string values of a runtime config layer
+/// with the value of the container environment variable `VAR`. `$${` escapes
+/// a literal `${`, which is only needed when expansion is enabled.
+fn expand_env_table(table: Table, path: &Path, prefix: &str) -> Result<Table> {
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let value = expand_env_value(value, path, &format!("{prefix}{name}"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn expand_env_value(value: Value, path: &Path, key: &str) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) => Value::String(expand_env(&s).map_err(|e| {
+            anyhow::anyhow!("failed to expand runtime config {path:?}: `{key}` {e}")
+        })?),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| expand_env_value(value, path, &format!("{key}[{i}]")))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Table(table) => Value::Table(expand_env_table(table, path, &format!("{key}."))?),
+        value => value,
+    })
+}
+
+fn expand_env(s: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let Some(end) = reference.find('}') else {
+                bail!("contains an unterminated environment variable reference");
+            };
+            let name = &reference[..end];
+            if name.is_empty() {
+                bail!("contains an empty environment variable reference");
+            }
+            let value = env::var(name).map_err(|_| {
+                anyhow::anyhow!("references environment variable `{name}` which is not set")
+            })?;
+            expanded.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Resolves the relative paths a runtime config layer configures against the
+/// given directory. Paths are the values of `path` keys and of keys ending in
+/// `_path`, `_file` or `_dir`, such as `ca_roots_file`. The settings of the
+/// triggers run by the shim are not passed on to Spin and are left as they are.
+fn resolve_relative_paths(mut layer: Table, dir: &Path) -> Table {
+    let trigger_settings = layer.remove(TRIGGER_SETTINGS_KEY);
+    let mut layer = resolve_paths_in_table(layer, dir);
+    if let Some(trigger_settings) = trigger_settings {
+        layer.insert(TRIGGER_SETTINGS_KEY.to_string(), trigger_settings);
+    }
+    layer
+}
+
+fn resolve_paths_in_table(table: Table, dir: &Path) -> Table {
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let value = resolve_paths_in_value(value, &name, dir);
+            (name, value)
+        })
+        .collect()
+}
+
+fn resolve_paths_in_value(value: Value, name: &str, dir: &Path) -> Value {
+    match value {
+        Value::String(s) if is_path_key(name) && !s.is_empty() && Path::new(&s).is_relative() => {
+            Value::String(dir.join(s).to_string_lossy().into_owned())
+        }
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| resolve_paths_in_value(value, name, dir))
+                .collect(),
+        ),
+        Value::Table(table) => Value::Table(resolve_paths_in_table(table, dir)),
+        value => value,
+    }
+}
+
+fn is_path_key(name: &str) -> bool {
+    name == "path"
+        || ["_path", "_file", "_dir"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+}
+
+/// Merges the runtime config layers in order.
+///
+/// Tables are merged recursively and values from later layers override values
+/// from earlier ones. It is an error for layers to disagree on whether a key
+/// is a table, or to configure the same table with a different `type`, as the
+/// options of one type do not apply to another.
+fn merge_layers(layers: Vec<(&Path, Table)>) -> Result<Table> {
+    let mut merged = Table::new();
+    let mut origins = HashMap::new();
+    for (path, layer) in layers {
+        merge_table(&mut merged, layer, path, "", &mut origins)?;
+    }
+    Ok(merged)
+}
+
+fn merge_table<'a>(
+    merged: &mut Table,
+    layer: Table,
+    layer_path: &'a Path,
+    prefix: &str,
+    origins: &mut HashMap<String, &'a Path>,
+) -> Result<()> {
+    if let (Some(Value::String(existing)), Some(Value::String(new))) =
+        (merged.get("type"), layer.get("type"))
+    {
+        if existing != new {
+            let key = format!("{prefix}type");
+            bail!(
+                "conflicting runtime configs: `{key}` is {existing:?} in {:?} but {new:?} in {layer_path:?}",
+                origins[&key]
+            );
+        }
+    }
+    for (name, value) in layer {
+        let key = format!("{prefix}{name}");
+        match (merged.get_mut(&name), value) {
+            (Some(Value::Table(existing)), Value::Table(table)) => {
+                merge_table(existing, table, layer_path, &format!("{key}."), origins)?;
+            }
+            (Some(existing), value) if existing.is_table() || value.is_table() => {
+                bail!(
+                    "conflicting runtime configs: `{key}` is a {} in {:?} but a {} in {layer_path:?}",
+                    existing.type_str(),
+                    origins[&key],
+                    value.type_str()
+                );
+            }
+            (_, value) => {
+                record_origins(&value, &key, layer_path, origins);
+                merged.insert(name, value);
+            }
+        }
+        origins.insert(key, layer_path);
+    }
+    Ok(())
+}
+
+/// Records the layer as the origin of the keys of a table it adds
+fn record_origins<'a>(
+    value: &Value,
+    key: &str,
+    layer_path: &'a Path,
+    origins: &mut HashMap<String, &'a Path>,
+) {
+    if let Value::Table(table) = value {
+        for (name, value) in table {
+            let key = format!("{key}.{name}");
+            record_origins(value, &key, layer_path, origins);
+            origins.insert(key, layer_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_layer(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn resolve(paths: &[&Path], expand: bool) -> Result<RuntimeConfig> {
+        resolve_runtime_config(&AppConfig {
+            runtime_config_paths: Some(paths.iter().map(|path| path.to_path_buf()).collect()),
+            expand_runtime_config: expand,
+            ..Default::default()
+        })
+    }
+
+    fn merge_files(paths: &[PathBuf]) -> Result<Table> {
+        merge_layers(
+            paths
+                .iter()
+                .map(|path| (path.as_path(), load_layer(path).unwrap()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn merge_layers_overrides_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write_layer(
+            dir.path(),
+            "base.toml",
+            r#"
+            [key_value_store.default]
+            type = "redis"
+            url = "redis://base:6379"
+
+            [sqlite_database.default]
+            type = "spin"
+            path = "/data/db.sqlite"
+            "#,
+        );
+        let overrides = write_layer(
+            dir.path(),
+            "overrides.toml",
+            r#"
+            [key_value_store.default]
+            url = "redis://prod:6379"
+
+            [llm_compute]
+            type = "remote_http"
+            url = "http://llm"
+            auth_token = "token"
+            "#,
+        );
+        let merged = merge_files(&[base, overrides]).unwrap();
+        let expected = r#"
+            [key_value_store.default]
+            type = "redis"
+            url = "redis://prod:6379"
+
+            [sqlite_database.default]
+            type = "spin"
+            path = "/data/db.sqlite"
+
+            [llm_compute]
+            type = "remote_http"
+            url = "http://llm"
+            auth_token = "token"
+            "#
+        .parse::<Table>()
+        .unwrap();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn merge_layers_rejects_conflicting_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write_layer(
+            dir.path(),
+            "base.toml",
+            r#"
+            [key_value_store.default]
+            type = "redis"
+            url = "redis://base:6379"
+            "#,
+        );
+        let overrides = write_layer(
+            dir.path(),
+            "overrides.toml",
+            r#"
+            [key_value_store.default]
+            type = "spin"
+            "#,
+        );
+        let e = merge_files(&[base.clone(), overrides.clone()]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("conflicting runtime configs: `key_value_store.default.type` is \"redis\" in {base:?} but \"spin\" in {overrides:?}")
+        );
+    }
+
+    #[test]
+    fn merge_layers_rejects_table_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write_layer(dir.path(), "base.toml", "[llm_compute]\ntype = \"spin\"\n");
+        let overrides = write_layer(dir.path(), "overrides.toml", "llm_compute = \"spin\"\n");
+        let e = merge_files(&[base.clone(), overrides.clone()]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("conflicting runtime configs: `llm_compute` is a table in {base:?} but a string in {overrides:?}")
+        );
+    }
+
+    #[test]
+    fn expand_env_references() {
+        temp_env::with_vars(
+            [
+                ("REDIS_HOST", Some("redis")),
+                ("REDIS_PASSWORD", Some("p@$$")),
+            ],
+            || {
+                assert_eq!(
+                    expand_env("redis://:${REDIS_PASSWORD}@${REDIS_HOST}:6379").unwrap(),
+                    "redis://:p@$$@redis:6379"
+                );
+                assert_eq!(
+                    expand_env("$5 and $${literal}").unwrap(),
+                    "$5 and ${literal}"
+                );
+                assert!(expand_env("${REDIS_HOST").is_err());
+                assert!(expand_env("${}").is_err());
+            },
+        );
+    }
+
+    #[test]
+    fn expand_env_names_file_and_key() {
+        let path = Path::new("/runtime-config.toml");
+        let layer = r#"
+            [key_value_store.default]
+            type = "redis"
+            url = "redis://${SPIN_TEST_UNSET_REDIS_HOST}:6379"
+            "#
+        .parse::<Table>()
+        .unwrap();
+        temp_env::with_var_unset("SPIN_TEST_UNSET_REDIS_HOST", || {
+            let e = expand_env_table(layer, path, "").unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "failed to expand runtime config \"/runtime-config.toml\": `key_value_store.default.url` references environment variable `SPIN_TEST_UNSET_REDIS_HOST` which is not set"
+            );
+        });
+    }
+
+    #[test]
+    fn resolve_runtime_config_expands_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_layer(
+            dir.path(),
+            "runtime-config.toml",
+            "[sqlite_database.default]\ntype = \"libsql\"\nurl = \"https://db\"\ntoken = \"${SPIN_TEST_DB_TOKEN}\"\n",
+        );
+        temp_env::with_var("SPIN_TEST_DB_TOKEN", Some("secret"), || {
+            let path = resolve(&[&config], true).unwrap().file.unwrap();
+            assert!(path.starts_with("/proc/self/fd"), "{path:?}");
+            let expanded = fs::read_to_string(path).unwrap().parse::<Table>().unwrap();
+            assert_eq!(
+                expanded["sqlite_database"]["default"]["token"].as_str(),
+                Some("secret")
+            );
+
+            // References are left as they are unless expansion is enabled
+            assert_eq!(
+                resolve(&[&config], false).unwrap().file,
+                Some(config.clone())
+            );
+        });
+    }
+
+    #[test]
+    fn resolve_runtime_config_extracts_trigger_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_layer(
+            dir.path(),
+            "runtime-config.toml",
+            "[key_value_store.default]\ntype = \"spin\"\n\n[trigger.kafka]\nbrokers = \"localhost:9092\"\n",
+        );
+        let runtime_config = resolve(&[&config], false).unwrap();
+        let file = runtime_config.file.as_ref().unwrap();
+        assert!(file.starts_with("/proc/self/fd"), "{file:?}");
+        let written = fs::read_to_string(file).unwrap().parse::<Table>().unwrap();
+        assert!(written.contains_key("key_value_store"));
+        assert!(!written.contains_key(TRIGGER_SETTINGS_KEY));
+
+        let app_json = r#"
+        {
+            "spin_lock_version": 1,
+            "metadata": {"triggers": {"kafka": {"group_id": "orders", "brokers": "manifest:9092"}}},
+            "components": [],
+            "triggers": []
+        }"#;
+        let mut locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
+        runtime_config
+            .apply_trigger_settings(&mut locked_app)
+            .unwrap();
+        assert_eq!(
+            locked_app.metadata[TRIGGERS_METADATA_KEY]["kafka"],
+            serde_json::json!({"group_id": "orders", "brokers": "localhost:9092"})
+        );
+    }
+
+    #[test]
+    fn resolve_runtime_config_resolves_relative_paths_per_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("base")).unwrap();
+        let base = write_layer(
+            &dir.path().join("base"),
+            "runtime-config.toml",
+            r#"
+            [sqlite_database.default]
+            type = "spin"
+            path = "data/db.sqlite"
+
+            [[client_tls]]
+            component_ids = ["api"]
+            hosts = ["db:5432"]
+            ca_roots_file = "certs/ca.pem"
+            "#,
+        );
+        let overrides = write_layer(
+            dir.path(),
+            "overrides.toml",
+            r#"
+            [key_value_store.default]
+            type = "spin"
+            path = "/data/kv.db"
+
+            [llm_compute]
+            type = "remote_http"
+            url = "http://llm"
+            "#,
+        );
+        let file = resolve(&[&base, &overrides], false).unwrap().file.unwrap();
+        let merged = fs::read_to_string(file).unwrap().parse::<Table>().unwrap();
+        let base_dir = dir.path().join("base");
+        assert_eq!(
+            merged["sqlite_database"]["default"]["path"].as_str(),
+            base_dir.join("data/db.sqlite").to_str()
+        );
+        assert_eq!(
+            merged["client_tls"][0]["ca_roots_file"].as_str(),
+            base_dir.join("certs/ca.pem").to_str()
+        );
+        assert_eq!(
+            merged["key_value_store"]["default"]["path"].as_str(),
+            Some("/data/kv.db")
+        );
+        assert_eq!(merged["llm_compute"]["url"].as_str(), Some("http://llm"));
+    }
+
+    #[test]
+    fn resolve_runtime_config_detects_variables_providers() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault =
+            "type = \"vault\"\nurl = \"http://vault:8200\"\ntoken = \"root\"\nmount = \"secret\"\n";
+        let providers = write_layer(
+            dir.path(),
+            "providers.toml",
+            &format!("[[variables_provider]]\n{vault}"),
+        );
+        let legacy = write_layer(
+            dir.path(),
+            "legacy.toml",
+            &format!("[[config_provider]]\n{vault}"),
+        );
+        let other = write_layer(dir.path(), "other.toml", "[llm_compute]\ntype = \"spin\"\n");
+        for (paths, expected) in [
+            (vec![&providers], true),
+            (vec![&legacy], true),
+            (vec![&other], false),
+            (vec![&other, &providers], true),
+        ] {
+            let paths = paths.into_iter().map(PathBuf::as_path).collect::<Vec<_>>();
+            let runtime_config = resolve(&paths, false).unwrap();
+            assert_eq!(
+                runtime_config.configures_variables_providers(),
+                expected,
+                "{paths:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_runtime_config_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write_layer(dir.path(), "base.toml", "[llm_compute]\ntype = \"spin\"\n");
+        let overrides = write_layer(
+            dir.path(),
+            "overrides.toml",
+            "[key_value_store.default]\ntype = \"spin\"\n",
+        );
+
+        // A single layer is used as is
+        assert_eq!(resolve(&[&base], false).unwrap().file, Some(base.clone()));
+
+        // Multiple layers are merged in memory
+        let path = resolve(&[&base, &overrides], false).unwrap().file.unwrap();
+        assert!(path.starts_with("/proc/self/fd"), "{path:?}");
+        let merged = fs::read_to_string(path).unwrap().parse::<Table>().unwrap();
+        assert!(merged.contains_key("llm_compute"));
+        assert!(merged.contains_key("key_value_store"));
+
+        // An empty list configures no runtime config
+        assert_eq!(resolve(&[], false).unwrap().file, None);
+
+        // Listed layers must exist
+        assert!(resolve(&[&dir.path().join("missing.toml")], false).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! This module contains the coordination of the graceful shutdown of the triggers
+//!
+//! When the shim receives a termination signal, it stops the [`Shutdown`] the
+//! triggers were started with and keeps running them for up to the drain
+//! period. Each trigger stops taking on new work, finishes its work in flight
+//! and then exits, so that the shim exits as soon as all of them drained:
+//!
+//! - The triggers implemented by the shim stop receiving messages and firing
+//!   schedules.
+//! - The HTTP trigger closes its listener, and each connection once the request
+//!   in flight on it was served.
+//! - The triggers built into Spin that cannot be told to stop, such as the
+//!   Redis, MQTT and SQS triggers, are refused new instances of components
+//!   through [`DrainHooks`], and are stopped, cancelling their subscriptions,
+//!   once their instances in flight were dropped.
+//! - Trigger plugins are sent SIGTERM and drained until they exit.
+
+use std::{future::Future, sync::Arc};
+
+use futures::future::{self, Either};
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use spin_factor_wasi::WasiFactor;
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use spin_factors_executor::{ExecutorHooks, FactorsInstanceBuilder};
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use spin_runtime_factors::TriggerFactors;
+use tokio::sync::watch;
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use wasmtime_wasi::{pipe::ClosedInputStream, HostInputStream, StdinStream};
+
+/// Tells the triggers that the shim is shutting down
+#[derive(Clone)]
+pub(crate) struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Shutdown {
+    /// Starts the shutdown, asking the triggers to stop taking on new work
+    pub(crate) fn stop(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Returns whether the shutdown started
+    pub(crate) fn is_stopping(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown started
+    pub(crate) async fn stopped(&self) {
+        let mut stopping = self.0.subscribe();
+        // The sender lives as long as `self`, so waiting cannot fail
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    /// Runs the future unless the shutdown starts first, such as when waiting
+    /// for the next message of a subscription. Returns `None` once the
+    /// shutdown started, dropping the future.
+    pub(crate) async fn unless_stopped<F: Future>(&self, future: F) -> Option<F::Output> {
+        if self.is_stopping() {
+            return None;
+        }
+        match future::select(Box::pin(future), Box::pin(self.stopped())).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(((), _)) => None,
+        }
+    }
+}
+
+/// Counts the work in flight, such as the connections of the HTTP trigger
+#[cfg(any(feature = "http", feature = "redis", feature = "mqtt", feature = "sqs"))]
+#[derive(Clone, Default)]
+pub(crate) struct InFlight(Arc<watch::Sender<usize>>);
+
+#[cfg(any(feature = "http", feature = "redis", feature = "mqtt", feature = "sqs"))]
+impl InFlight {
+    /// Counts work as in flight until the returned guard is dropped
+    pub(crate) fn start(&self) -> InFlightGuard {
+        self.0.send_modify(|count| *count += 1);
+        InFlightGuard(self.0.clone())
+    }
+
+    /// Resolves once no work is in flight
+    pub(crate) async fn finished(&self) {
+        let mut count = self.0.subscribe();
+        // The sender lives as long as `self`, so waiting cannot fail
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+}
+
+/// Work in flight, which finished once dropped
+#[cfg(any(feature = "http", feature = "redis", feature = "mqtt", feature = "sqs"))]
+pub(crate) struct InFlightGuard(Arc<watch::Sender<usize>>);
+
+#[cfg(any(feature = "http", feature = "redis", feature = "mqtt", feature = "sqs"))]
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Hooks of the factors executor of a trigger that cannot be told to stop
+/// taking on new work. Once the shutdown started, new instances of components
+/// are refused, and the instances in flight are counted until they are dropped.
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+#[derive(Clone)]
+pub(crate) struct DrainHooks {
+    pub(crate) shutdown: Shutdown,
+    pub(crate) instances: InFlight,
+}
+
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+impl<U> ExecutorHooks<TriggerFactors, U> for DrainHooks {
+    fn prepare_instance(
+        &self,
+        builder: &mut FactorsInstanceBuilder<TriggerFactors, U>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.shutdown.is_stopping(),
+            "the app is shutting down, no longer taking on new work"
+        );
+        // Spin does not tell when an instance is dropped, but its WASI context
+        // lives as long as the instance
+        if let Some(wasi) = builder.factor_builder::<WasiFactor>() {
+            wasi.stdin(InFlightStdin {
+                _instance: self.instances.start(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The closed standard input of an instance in flight
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+struct InFlightStdin {
+    _instance: InFlightGuard,
+}
+
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+impl StdinStream for InFlightStdin {
+    fn stream(&self) -> Box<dyn HostInputStream> {
+        ClosedInputStream.stream()
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+/// CLI args of the triggers implemented by the shim, which stop taking on new
+/// work once the shutdown started
+#[cfg(any(
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct ShutdownArgs {
+    #[clap(skip)]
+    pub(crate) shutdown: Shutdown,
+}
+
+#[cfg(any(
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
+impl ShutdownArgs {
+    pub(crate) fn new(shutdown: &Shutdown) -> Self {
+        Self {
+            shutdown: shutdown.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unless_stopped_stops_waiting_on_shutdown() {
+        let shutdown = Shutdown::default();
+        assert_eq!(shutdown.unless_stopped(async { 1 }).await, Some(1));
+
+        let waiting = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.unless_stopped(future::pending::<()>()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!shutdown.is_stopping());
+        shutdown.stop();
+        assert_eq!(waiting.await.unwrap(), None);
+        assert!(shutdown.is_stopping());
+        assert_eq!(shutdown.unless_stopped(async { 1 }).await, None);
+    }
+
+    #[cfg(any(feature = "http", feature = "redis", feature = "mqtt", feature = "sqs"))]
+    #[tokio::test]
+    async fn work_is_in_flight_until_dropped() {
+        let in_flight = InFlight::default();
+        in_flight.finished().await;
+
+        let first = in_flight.start();
+        let second = in_flight.start();
+        let finished = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move { in_flight.finished().await }
+        });
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!finished.is_finished());
+        drop(second);
+        finished.await.unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{env, fs::File, io::Write, path::PathBuf};
 
 use anyhow::{Context, Result};
 use containerd_shim_wasm::container::RuntimeContext;
@@ -7,25 +7,87 @@ use oci_spec::image::MediaType;
 use spin_app::locked::LockedApp;
 use spin_loader::{cache::Cache, FilesMountStrategy};
 
-use crate::{constants, utils::handle_archive_layer};
+use crate::{config::AppConfig, constants, engine::SpinEngine, utils::handle_archive_layer};
 
 #[derive(Clone)]
 pub enum Source {
     File(PathBuf),
-    Oci,
+    Oci(Option<ImageReference>),
 }
 
 impl std::fmt::Debug for Source {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Source::File(path) => write!(f, "File({})", path.display()),
-            Source::Oci => write!(f, "Oci"),
+            Source::Oci(Some(image)) => write!(f, "Oci({})", image.reference()),
+            Source::Oci(None) => write!(f, "Oci"),
         }
     }
 }
 
+/// Reference to the image that an OCI application was distributed with
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageReference {
+    /// Image name, including the registry, repository and tag
+    pub(crate) name: String,
+    /// Digest of the image manifest, if known
+    pub(crate) digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Returns the image of the container, as annotated by the container
+    /// runtime. The `SPIN_OCI_IMAGE_REFERENCE` and `SPIN_OCI_IMAGE_DIGEST`
+    /// environment variables of the container override the annotated image,
+    /// such as when the container runtime does not annotate it.
+    pub(crate) fn from_env_or(annotated: Option<&Self>) -> Option<Self> {
+        let mut image = match env::var(constants::SPIN_OCI_IMAGE_REFERENCE_ENV) {
+            Ok(reference) => Self::parse(&reference),
+            Err(_) => annotated?.clone(),
+        };
+        if let Ok(digest) = env::var(constants::SPIN_OCI_IMAGE_DIGEST_ENV) {
+            image.digest = Some(digest);
+        }
+        Some(image)
+    }
+
+    pub(crate) fn parse(reference: &str) -> Self {
+        match reference.split_once('@') {
+            Some((name, digest)) => Self {
+                name: name.to_string(),
+                digest: Some(digest.to_string()),
+            },
+            None => Self {
+                name: reference.to_string(),
+                digest: None,
+            },
+        }
+    }
+
+    /// Returns the full reference, pinned to the digest if it is known
+    pub(crate) fn reference(&self) -> String {
+        match &self.digest {
+            Some(digest) => format!("{}@{}", self.name, digest),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Returns the image as OpenTelemetry resource attributes
+    pub(crate) fn resource_attributes(&self) -> Vec<(&'static str, &str)> {
+        let mut attributes = vec![("container.image.name", self.name.as_str())];
+        if let Some(digest) = &self.digest {
+            attributes.push(("oci.manifest.digest", digest));
+        }
+        attributes
+    }
+}
+
 impl Source {
-    pub(crate) async fn from_ctx(ctx: &impl RuntimeContext, cache: &Cache) -> Result<Self> {
+    pub(crate) async fn from_ctx(
+        ctx: &impl RuntimeContext,
+        cache: &Cache,
+        config: &AppConfig,
+        engine: &SpinEngine,
+    ) -> Result<Self> {
         match ctx.entrypoint().source {
             containerd_shim_wasm::container::Source::File(_) => {
                 Ok(Source::File(constants::SPIN_MANIFEST_FILE_PATH.into()))
@@ -42,12 +104,12 @@ impl Source {
                         MediaType::Other(name)
                             if name == spin_oci::client::SPIN_APPLICATION_MEDIA_TYPE =>
                         {
-                            let path = PathBuf::from("/spin.json");
+                            let path = config.locked_app_path();
                             log::info!("writing spin oci config to {:?}", path);
                             File::create(&path)
-                                .context("failed to create spin.json")?
+                                .with_context(|| format!("failed to create {path:?}"))?
                                 .write_all(&artifact.layer)
-                                .context("failed to write spin.json")?;
+                                .with_context(|| format!("failed to write {path:?}"))?;
                         }
                         MediaType::Other(name) if name == constants::OCI_LAYER_MEDIA_TYPE_WASM => {
                             log::info!(
@@ -55,9 +117,8 @@ impl Source {
                                 artifact.layer.len(),
                                 cache.manifests_dir()
                             );
-                            cache
-                                .write_wasm(&artifact.layer, &artifact.config.digest())
-                                .await?;
+                            let wasm = engine.verify_wasm_layer(artifact)?;
+                            cache.write_wasm(wasm, &artifact.config.digest()).await?;
                         }
                         MediaType::Other(name) if name == spin_oci::client::DATA_MEDIATYPE => {
                             log::debug!(
@@ -85,31 +146,128 @@ impl Source {
                         }
                     }
                 }
-                Ok(Source::Oci)
+                let image = ImageReference::from_env_or(engine.image.as_ref());
+                match &image {
+                    Some(image) => info!(" >>> spin oci application image {}", image.reference()),
+                    None => log::debug!(
+                        "<<< the image is not annotated and {} is not set; image reference is unknown",
+                        constants::SPIN_OCI_IMAGE_REFERENCE_ENV
+                    ),
+                }
+                Ok(Source::Oci(image))
             }
         }
     }
 
-    pub(crate) async fn to_locked_app(&self, cache: &Cache) -> Result<LockedApp> {
+    pub(crate) async fn to_locked_app(
+        &self,
+        cache: &Cache,
+        config: &AppConfig,
+    ) -> Result<LockedApp> {
         let locked_app = match self {
             Source::File(source) => {
                 // TODO: This should be configurable, see https://github.com/deislabs/containerd-wasm-shims/issues/166
                 // TODO: ^^ Move aforementioned issue to this repo
                 let files_mount_strategy = FilesMountStrategy::Direct;
-                spin_loader::from_file(&source, files_mount_strategy, None).await
+                spin_loader::from_file(
+                    &source,
+                    files_mount_strategy,
+                    Some(config.cache_dir.clone()),
+                )
+                .await
             }
-            Source::Oci => {
-                let working_dir = PathBuf::from("/");
-                let loader = spin_oci::OciLoader::new(working_dir);
+            Source::Oci(image) => {
+                let loader = spin_oci::OciLoader::new(config.scratch_dir.clone());
 
-                // TODO: what is the best way to get this info? It isn't used only saved in the locked file
-                let reference = "docker.io/library/wasmtest_spin:latest";
+                let reference = image
+                    .as_ref()
+                    .map(ImageReference::reference)
+                    .unwrap_or_else(|| constants::SPIN_OCI_IMAGE_REFERENCE_UNKNOWN.to_string());
 
-                loader
-                    .load_from_cache(PathBuf::from("/spin.json"), reference, cache)
+                let mut locked_app = loader
+                    .load_from_cache(config.locked_app_path(), &reference, cache)
                     .await
+                    .with_context(|| format!("failed to load spin oci application {reference}"))?;
+                match image {
+                    Some(image) => {
+                        locked_app
+                            .metadata
+                            .insert("oci_image_name".into(), image.name.clone().into());
+                        if let Some(digest) = &image.digest {
+                            locked_app
+                                .metadata
+                                .insert("oci_image_digest".into(), digest.clone().into());
+                        }
+                    }
+                    // Do not report the placeholder reference as the origin of the app
+                    None => {
+                        locked_app.metadata.remove("origin");
+                    }
+                }
+                Ok(locked_app)
             }
         }?;
         Ok(locked_app)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_image_reference() {
+        let image = ImageReference::parse("ghcr.io/spinkube/app:v1");
+        assert_eq!(image.name, "ghcr.io/spinkube/app:v1");
+        assert_eq!(image.digest, None);
+        assert_eq!(image.reference(), "ghcr.io/spinkube/app:v1");
+        assert_eq!(
+            image.resource_attributes(),
+            [("container.image.name", "ghcr.io/spinkube/app:v1")]
+        );
+
+        let image = ImageReference::parse("ghcr.io/spinkube/app:v1@sha256:1234");
+        assert_eq!(image.name, "ghcr.io/spinkube/app:v1");
+        assert_eq!(image.digest.as_deref(), Some("sha256:1234"));
+        assert_eq!(image.reference(), "ghcr.io/spinkube/app:v1@sha256:1234");
+        assert_eq!(
+            image.resource_attributes(),
+            [
+                ("container.image.name", "ghcr.io/spinkube/app:v1"),
+                ("oci.manifest.digest", "sha256:1234")
+            ]
+        );
+    }
+
+    #[test]
+    fn image_reference_from_env() {
+        let annotated = ImageReference::parse("ghcr.io/spinkube/annotated:v1");
+        temp_env::with_vars(
+            [
+                (
+                    constants::SPIN_OCI_IMAGE_REFERENCE_ENV,
+                    Some("ghcr.io/spinkube/app:v1"),
+                ),
+                (constants::SPIN_OCI_IMAGE_DIGEST_ENV, Some("sha256:1234")),
+            ],
+            || {
+                let image = ImageReference::from_env_or(Some(&annotated))
+                    .expect("image reference not found");
+                assert_eq!(image.reference(), "ghcr.io/spinkube/app:v1@sha256:1234");
+            },
+        );
+        temp_env::with_vars_unset(
+            [
+                constants::SPIN_OCI_IMAGE_REFERENCE_ENV,
+                constants::SPIN_OCI_IMAGE_DIGEST_ENV,
+            ],
+            || {
+                assert_eq!(
+                    ImageReference::from_env_or(Some(&annotated)),
+                    Some(annotated.clone())
+                );
+                assert!(ImageReference::from_env_or(None).is_none());
+            },
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! This module contains the telemetry of the shim
+//!
+//! Telemetry is set up like `spin_telemetry::init` sets it up for `spin up`:
+//! events are logged to stderr, filtered by `RUST_LOG`, and traces and metrics
+//! are exported over OTLP when an endpoint is configured through the standard
+//! `OTEL_EXPORTER_OTLP_*` environment variables. The resource attributes are
+//! taken from the [`AppConfig`], which adds the image of OCI applications to
+//! those of `OTEL_RESOURCE_ATTRIBUTES`, rather than read from the environment
+//! by the OpenTelemetry SDK.
+
+use std::{env, io::IsTerminal, time::Duration};
+
+use anyhow::{bail, Result};
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::Protocol;
+use opentelemetry_sdk::{
+    metrics::{
+        reader::{DefaultAggregationSelector, DefaultTemporalitySelector},
+        PeriodicReader, SdkMeterProvider,
+    },
+    propagation::TraceContextPropagator,
+    resource::{Resource, TelemetryResourceDetector},
+    runtime,
+    trace::{self, TracerProvider},
+};
+use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+use crate::config::AppConfig;
+
+/// How long the detection of the resource of the SDK may take
+const RESOURCE_DETECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Flushes and shuts down the exporters when dropped
+pub(crate) struct TelemetryGuard {
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+        if let Some(Err(e)) = self.meter_provider.as_ref().map(SdkMeterProvider::shutdown) {
+            log::warn!(" >>> failed to shut down metrics exporter: {e}");
+        }
+    }
+}
+
+/// Installs the global tracing subscriber, exporting the telemetry of the app
+/// with the resource attributes of the [`AppConfig`]. The exporters are shut
+/// down when the returned guard is dropped.
+///
+/// Must be called within a Tokio runtime, which the exporters run on.
+pub(crate) fn init(config: &AppConfig, version: &str) -> Result<TelemetryGuard> {
+    let resource = resource(config.telemetry_resource_attributes.as_deref(), version);
+    let fmt_layer = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_filter(
+            EnvFilter::from_default_env()
+                .add_directive("wasmtime_wasi_http=warn".parse()?)
+                .add_directive("watchexec=off".parse()?),
+        );
+    let tracer_provider = otlp_protocol("TRACES")?
+        .map(|protocol| tracer_provider(protocol, resource.clone()))
+        .transpose()?;
+    let meter_provider = otlp_protocol("METRICS")?
+        .map(|protocol| meter_provider(protocol, resource))
+        .transpose()?;
+    let trace_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("spin"))
+            .with_threads(false)
+    });
+    let metrics_layer = meter_provider
+        .clone()
+        .map(tracing_opentelemetry::MetricsLayer::new);
+    let subscriber = Registry::default()
+        .with(fmt_layer)
+        .with(trace_layer)
+        .with(metrics_layer);
+    tracing::subscriber::set_global_default(subscriber)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    if let Some(provider) = tracer_provider {
+        global::set_tracer_provider(provider);
+    }
+    Ok(TelemetryGuard { meter_provider })
+}
+
+/// Returns the protocol the signal, `TRACES` or `METRICS`, is exported with,
+/// or `None` if no OTLP endpoint is configured for it or the SDK is disabled
+fn otlp_protocol(signal: &str) -> Result<Option<Protocol>> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    if var("OTEL_SDK_DISABLED").is_some_and(|disabled| disabled.trim().eq_ignore_ascii_case("true"))
+    {
+        return Ok(None);
+    }
+    if var("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && var(&format!("OTEL_EXPORTER_OTLP_{signal}_ENDPOINT")).is_none()
+    {
+        return Ok(None);
+    }
+    let protocol = var(&format!("OTEL_EXPORTER_OTLP_{signal}_PROTOCOL"))
+        .or_else(|| var("OTEL_EXPORTER_OTLP_PROTOCOL"));
+    match protocol.as_deref().map(str::trim) {
+        None | Some("http/protobuf") => Ok(Some(Protocol::HttpBinary)),
+        Some("grpc") => Ok(Some(Protocol::Grpc)),
+        Some(other) => bail!("unsupported OTLP protocol {other:?}"),
+    }
+}
+
+/// Builds the provider of the tracers, exporting spans in batches
+fn tracer_provider(protocol: Protocol, resource: Resource) -> Result<TracerProvider> {
+    let exporter = match protocol {
+        Protocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .build_span_exporter()?,
+        _ => opentelemetry_otlp::new_exporter()
+            .http()
+            .build_span_exporter()?,
+    };
+    Ok(TracerProvider::builder()
+        .with_config(trace::Config::default().with_resource(resource))
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build())
+}
+
+/// Builds the provider of the meters, exporting metrics periodically
+fn meter_provider(protocol: Protocol, resource: Resource) -> Result<SdkMeterProvider> {
+    let aggregation = Box::new(DefaultAggregationSelector::new());
+    let temporality = Box::new(DefaultTemporalitySelector::new());
+    let exporter = match protocol {
+        Protocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .build_metrics_exporter(aggregation, temporality)?,
+        _ => opentelemetry_otlp::new_exporter()
+            .http()
+            .build_metrics_exporter(aggregation, temporality)?,
+    };
+    let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+    Ok(SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build())
+}
+
+/// Returns the resource the telemetry is exported with: Spin as the service,
+/// unless `OTEL_SERVICE_NAME` names another one, with the given attributes in
+/// the format of `OTEL_RESOURCE_ATTRIBUTES`
+fn resource(attributes: Option<&str>, version: &str) -> Resource {
+    let service = [
+        KeyValue::new("service.name", "spin"),
+        KeyValue::new("service.version", version.to_string()),
+    ];
+    let service_name = env::var("OTEL_SERVICE_NAME")
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+        .map(|name| KeyValue::new("service.name", name));
+    Resource::from_detectors(
+        RESOURCE_DETECTION_TIMEOUT,
+        vec![Box::new(TelemetryResourceDetector)],
+    )
+    .merge(&Resource::new(service))
+    .merge(&Resource::new(parse_resource_attributes(
+        attributes.unwrap_or_default(),
+    )))
+    .merge(&Resource::new(service_name))
+}
+
+/// Parses attributes in the format of `OTEL_RESOURCE_ATTRIBUTES` the way the
+/// OpenTelemetry SDK does, skipping malformed ones
+fn parse_resource_attributes(attributes: &str) -> Vec<KeyValue> {
+    attributes
+        .split_terminator(',')
+        .filter_map(|attribute| attribute.split_once('='))
+        .filter(|(_, value)| !value.contains('='))
+        .map(|(key, value)| KeyValue::new(key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{Key, Value};
+
+    use super::*;
+
+    #[test]
+    fn resource_has_app_attributes() {
+        let attributes = "container.image.name=ghcr.io/org/app, team=platform,bad,a=b=c";
+        temp_env::with_var_unset("OTEL_SERVICE_NAME", || {
+            let resource = resource(Some(attributes), "1.2.3");
+            let get = |key: &'static str| resource.get(Key::from_static_str(key));
+            assert_eq!(get("service.name"), Some(Value::from("spin")));
+            assert_eq!(get("service.version"), Some(Value::from("1.2.3")));
+            assert_eq!(
+                get("container.image.name"),
+                Some(Value::from("ghcr.io/org/app"))
+            );
+            assert_eq!(get("team"), Some(Value::from("platform")));
+            assert_eq!(get("a"), None);
+        });
+    }
+
+    #[test]
+    fn resource_service_name_from_env() {
+        temp_env::with_var("OTEL_SERVICE_NAME", Some("orders"), || {
+            let resource = resource(Some("service.name=ignored"), "1.2.3");
+            assert_eq!(
+                resource.get(Key::from_static_str("service.name")),
+                Some(Value::from("orders"))
+            );
+        });
+    }
+
+    #[test]
+    fn otlp_protocol_from_env() {
+        temp_env::with_vars(
+            [
+                ("OTEL_SDK_DISABLED", None),
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", None::<&str>),
+                ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", None),
+                ("OTEL_EXPORTER_OTLP_PROTOCOL", None),
+                ("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", None),
+            ],
+            || assert_eq!(otlp_protocol("TRACES").unwrap(), None),
+        );
+        temp_env::with_vars(
+            [
+                ("OTEL_SDK_DISABLED", None),
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", Some("http://collector:4317")),
+                ("OTEL_EXPORTER_OTLP_PROTOCOL", Some("grpc")),
+                ("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", None),
+            ],
+            || {
+                assert_eq!(otlp_protocol("TRACES").unwrap(), Some(Protocol::Grpc));
+                assert_eq!(otlp_protocol("METRICS").unwrap(), Some(Protocol::Grpc));
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("OTEL_SDK_DISABLED", Some("true")),
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", Some("http://collector:4317")),
+            ],
+            || assert_eq!(otlp_protocol("TRACES").unwrap(), None),
+        );
+        temp_env::with_vars(
+            [
+                ("OTEL_SDK_DISABLED", None),
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", Some("http://collector:4317")),
+                ("OTEL_EXPORTER_OTLP_PROTOCOL", Some("http/json")),
+            ],
+            || assert!(otlp_protocol("TRACES").is_err()),
+        );
+    }
+}
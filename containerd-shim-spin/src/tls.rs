@@ -0,0 +1,271 @@
+//! This module contains the logic for serving HTTPS from certificate files mounted into the container
+
+use std::{
+    convert::Infallible,
+    env, fs,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use rustls::{
+    crypto::ring::{self, sign::any_supported_type},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::constants;
+
+/// Location of the PEM encoded certificate and private key used by the HTTP trigger
+#[derive(Clone, Debug)]
+pub(crate) struct TlsFiles {
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Returns the TLS files configured through the `SPIN_HTTP_TLS_CERT` and
+    /// `SPIN_HTTP_TLS_KEY` environment variables, if any.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let cert = env::var(constants::SPIN_HTTP_TLS_CERT_ENV).ok();
+        let key = env::var(constants::SPIN_HTTP_TLS_KEY_ENV).ok();
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+            })),
+            (None, None) => Ok(None),
+            _ => bail!(
+                "both {} and {} must be set to serve HTTPS",
+                constants::SPIN_HTTP_TLS_CERT_ENV,
+                constants::SPIN_HTTP_TLS_KEY_ENV
+            ),
+        }
+    }
+
+    /// Reads the current content of the certificate and key
+    fn read(&self) -> Result<TlsContent> {
+        let read = |path: &PathBuf| {
+            fs::read(path).with_context(|| format!("failed to read TLS file {path:?}"))
+        };
+        Ok(TlsContent {
+            cert: read(&self.cert)?,
+            key: read(&self.key)?,
+        })
+    }
+
+    /// Resolves with the new content of the certificate and key once it
+    /// differs from the given content.
+    async fn changed(&self, current: TlsContent) -> TlsContent {
+        let mut pending = None;
+        loop {
+            tokio::time::sleep(constants::SPIN_HTTP_TLS_RELOAD_INTERVAL).await;
+            if let Some(changed) = self.check(&current, &mut pending) {
+                return changed;
+            }
+        }
+    }
+
+    /// Checks the files once, returning their content if it changed and was
+    /// the same at the previous check. A certificate and key updated one after
+    /// the other are only picked up once both were updated, and files that
+    /// briefly disappear while a mounted secret is updated are checked again.
+    fn check(&self, current: &TlsContent, pending: &mut Option<TlsContent>) -> Option<TlsContent> {
+        let content = match self.read() {
+            Ok(content) => content,
+            Err(e) => {
+                log::debug!("<<< unable to check TLS files for changes: {e:?}");
+                *pending = None;
+                return None;
+            }
+        };
+        if &content == current {
+            *pending = None;
+            None
+        } else if pending.as_ref() == Some(&content) {
+            pending.take()
+        } else {
+            *pending = Some(content);
+            None
+        }
+    }
+}
+
+/// Content of the certificate and key
+#[derive(Clone, PartialEq)]
+struct TlsContent {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl TlsContent {
+    /// Parses the PEM encoded certificate chain and private key
+    fn certified_key(&self) -> Result<Arc<CertifiedKey>> {
+        let certs = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to parse TLS certificate")?;
+        anyhow::ensure!(!certs.is_empty(), "no certificate found in TLS certificate");
+        let key = rustls_pemfile::private_key(&mut self.key.as_slice())
+            .context("failed to parse TLS private key")?
+            .context("no private key found in TLS key")?;
+        let key = any_supported_type(&key).context("unsupported TLS private key")?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+}
+
+/// The certificate and key served to clients, which are replaced when the TLS
+/// files change. Connections already established keep the certificate they
+/// were accepted with.
+#[derive(Debug)]
+struct ReloadingCert(RwLock<Arc<CertifiedKey>>);
+
+impl ReloadingCert {
+    /// Serves the changed certificate and key, unless they are invalid
+    fn update(&self, content: &TlsContent) {
+        match content.certified_key() {
+            Ok(key) => {
+                *self.0.write().unwrap() = key;
+                info!(" >>> TLS certificate changed, serving the new one");
+            }
+            Err(e) => log::error!(
+                " >>> failed to load the changed TLS certificate, serving the previous one: {e:?}"
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// Returns the acceptor terminating TLS with the certificate and key of the
+/// files, along with the future that reloads them whenever the files change,
+/// so that rotated secrets are picked up without restarting the pod or
+/// closing the listener.
+///
+/// Only the initial certificate and key must be valid. Invalid changes are
+/// ignored, and the previous certificate is served until the files change
+/// again.
+pub(crate) fn acceptor(tls: TlsFiles) -> Result<(TlsAcceptor, impl Future<Output = Infallible>)> {
+    let mut current = tls.read()?;
+    let cert = Arc::new(ReloadingCert(RwLock::new(current.certified_key()?)));
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("failed to configure TLS")?
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    let reload = async move {
+        loop {
+            current = tls.changed(current).await;
+            cert.update(&current);
+        }
+    };
+    Ok((TlsAcceptor::from(Arc::new(config)), reload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_files_from_env() {
+        temp_env::with_vars(
+            [
+                (constants::SPIN_HTTP_TLS_CERT_ENV, Some("/certs/tls.crt")),
+                (constants::SPIN_HTTP_TLS_KEY_ENV, Some("/certs/tls.key")),
+            ],
+            || {
+                let tls = TlsFiles::from_env().unwrap().expect("TLS files not found");
+                assert_eq!(tls.cert, PathBuf::from("/certs/tls.crt"));
+                assert_eq!(tls.key, PathBuf::from("/certs/tls.key"));
+            },
+        );
+        temp_env::with_vars(
+            [
+                (constants::SPIN_HTTP_TLS_CERT_ENV, Some("/certs/tls.crt")),
+                (constants::SPIN_HTTP_TLS_KEY_ENV, None),
+            ],
+            || {
+                assert!(TlsFiles::from_env().is_err());
+            },
+        );
+        temp_env::with_vars_unset(
+            [
+                constants::SPIN_HTTP_TLS_CERT_ENV,
+                constants::SPIN_HTTP_TLS_KEY_ENV,
+            ],
+            || {
+                assert!(TlsFiles::from_env().unwrap().is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn changes_are_picked_up_once_settled() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsFiles {
+            cert: dir.path().join("tls.crt"),
+            key: dir.path().join("tls.key"),
+        };
+        assert!(tls.read().is_err());
+        fs::write(&tls.cert, "cert").unwrap();
+        fs::write(&tls.key, "key").unwrap();
+        let current = tls.read().unwrap();
+        let mut pending = None;
+        assert!(tls.check(&current, &mut pending).is_none());
+
+        // The certificate was rotated, but the key was not yet
+        fs::write(&tls.cert, "rotated cert").unwrap();
+        assert!(tls.check(&current, &mut pending).is_none());
+        fs::write(&tls.key, "rotated key").unwrap();
+        assert!(tls.check(&current, &mut pending).is_none());
+        // The files briefly disappear
+        fs::remove_file(&tls.key).unwrap();
+        assert!(tls.check(&current, &mut pending).is_none());
+        fs::write(&tls.key, "rotated key").unwrap();
+        assert!(tls.check(&current, &mut pending).is_none());
+        let changed = tls
+            .check(&current, &mut pending)
+            .expect("change not picked up");
+        assert_eq!(changed.cert, b"rotated cert");
+        assert_eq!(changed.key, b"rotated key");
+        assert!(tls.check(&changed, &mut pending).is_none());
+    }
+
+    /// A self-signed certificate and its key for the given name
+    fn tls_content(name: &str) -> TlsContent {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        TlsContent {
+            cert: cert.cert.pem().into_bytes(),
+            key: cert.key_pair.serialize_pem().into_bytes(),
+        }
+    }
+
+    #[test]
+    fn certificate_is_replaced_unless_invalid() {
+        let content = tls_content("one.example.com");
+        let cert = ReloadingCert(RwLock::new(content.certified_key().unwrap()));
+        let served = || cert.0.read().unwrap().cert.clone();
+        let first = served();
+
+        let rotated = tls_content("two.example.com");
+        cert.update(&rotated);
+        assert_ne!(served(), first);
+        assert_eq!(served(), rotated.certified_key().unwrap().cert);
+
+        // A key that was not rotated along with the certificate is not PEM
+        let invalid = TlsContent {
+            cert: content.cert.clone(),
+            key: b"not a key".to_vec(),
+        };
+        assert!(invalid.certified_key().is_err());
+        cert.update(&invalid);
+        assert_eq!(served(), rotated.certified_key().unwrap().cert);
+    }
+}
@@ -1,64 +1,239 @@
-use std::{
-    collections::HashSet,
-    future::Future,
-    path::{Path, PathBuf},
-    pin::Pin,
-};
+use std::{future::Future, pin::Pin};
 
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+use anyhow::Context;
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use futures::future::{self, Either};
+#[cfg(any(
+    feature = "redis",
+    feature = "mqtt",
+    feature = "sqs",
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
 use log::info;
-use spin_app::{locked::LockedApp, App};
-use spin_runtime_factors::{FactorsBuilder, TriggerFactors};
+use spin_app::App;
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+use spin_factors::RuntimeFactors;
+use spin_runtime_factors::TriggerFactors;
 use spin_trigger::{
     cli::{FactorsConfig, TriggerAppBuilder, UserProvidedPath},
     loader::ComponentLoader,
-    Trigger,
+    Trigger, TriggerApp,
 };
-use spin_trigger_http::HttpTrigger;
-use spin_trigger_redis::RedisTrigger;
-use trigger_command::CommandTrigger;
-use trigger_mqtt::MqttTrigger;
-use trigger_sqs::SqsTrigger;
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+use wasmtime::component::Val;
+#[cfg(feature = "command")]
+use wasmtime_wasi::I32Exit;
 
-use crate::constants::{RUNTIME_CONFIG_PATH, SPIN_DEFAULT_STATE_DIR, SPIN_TRIGGER_WORKING_DIR};
+#[cfg(feature = "command")]
+use crate::constants::SPIN_COMMAND_TRAP_EXIT_CODE;
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use crate::shutdown::{DrainHooks, InFlight, Shutdown};
+use crate::{
+    config::AppConfig,
+    constants::SPIN_TRIGGER_WORKING_DIR,
+    source::Source,
+    variables::{FactorsArgs, FactorsBuilder},
+};
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+use crate::{constants, exit_policy::Backoff};
 
-pub(crate) const HTTP_TRIGGER_TYPE: &str = <HttpTrigger as Trigger<TriggerFactors>>::TYPE;
-pub(crate) const REDIS_TRIGGER_TYPE: &str = <RedisTrigger as Trigger<TriggerFactors>>::TYPE;
-pub(crate) const SQS_TRIGGER_TYPE: &str = <SqsTrigger as Trigger<TriggerFactors>>::TYPE;
-pub(crate) const MQTT_TRIGGER_TYPE: &str = <MqttTrigger as Trigger<TriggerFactors>>::TYPE;
-pub(crate) const COMMAND_TRIGGER_TYPE: &str = <CommandTrigger as Trigger<TriggerFactors>>::TYPE;
+/// A running trigger, which resolves when the trigger exits
+pub(crate) type TriggerFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>>>>;
 
-/// Run the trigger with the given CLI args, [`App`] and [`ComponentLoader`].
+/// Run the trigger with the given CLI args, [`App`], [`ComponentLoader`] and
+/// [`AppConfig`].
+#[cfg(any(
+    feature = "command",
+    feature = "cron",
+    feature = "kafka",
+    feature = "nats",
+    feature = "amqp"
+))]
 pub(crate) async fn run<T>(
     cli_args: T::CliArgs,
     app: App,
     loader: &ComponentLoader,
-) -> anyhow::Result<Pin<Box<dyn Future<Output = anyhow::Result<()>>>>>
+    config: &AppConfig,
+) -> anyhow::Result<TriggerFuture>
+where
+    T: Trigger<TriggerFactors> + 'static,
+{
+    info!(" >>> running {} trigger", T::TYPE);
+    let trigger = T::new(cli_args, &app)?;
+    let args = FactorsArgs::new(config.variables.clone());
+    let (trigger, trigger_app) = build(trigger, app, loader, config, args).await?;
+    Ok(Box::pin(trigger.run(trigger_app)))
+}
+
+/// Run a trigger that cannot be told to stop taking on new work, such as the
+/// Redis trigger, until it drained after the shutdown started.
+///
+/// Once the shutdown started, the trigger is refused new instances of its
+/// components. It is stopped as soon as the instances in flight were dropped,
+/// which cancels its subscriptions.
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+pub(crate) async fn run_until_shutdown<T>(
+    cli_args: T::CliArgs,
+    app: App,
+    loader: &ComponentLoader,
+    config: &AppConfig,
+    shutdown: &Shutdown,
+) -> anyhow::Result<TriggerFuture>
 where
     T: Trigger<TriggerFactors> + 'static,
 {
     info!(" >>> running {} trigger", T::TYPE);
     let trigger = T::new(cli_args, &app)?;
-    let builder: TriggerAppBuilder<_, FactorsBuilder> = TriggerAppBuilder::new(trigger);
+    let drain = DrainHooks {
+        shutdown: shutdown.clone(),
+        instances: InFlight::default(),
+    };
+    let args = FactorsArgs::new(config.variables.clone()).with_drain(drain.clone());
+    let (trigger, trigger_app) = build(trigger, app, loader, config, args).await?;
+    Ok(Box::pin(async move {
+        let mut running = Box::pin(trigger.run(trigger_app));
+        if let Some(result) = drain.shutdown.unless_stopped(running.as_mut()).await {
+            return result;
+        }
+        info!(" >>> {} trigger stopped taking on new work", T::TYPE);
+        match future::select(running, Box::pin(drain.instances.finished())).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Ok(()),
+        }
+    }))
+}
+
+/// Builds the [`TriggerApp`] of the trigger with the given factors args, for
+/// triggers that are not run through [`Trigger::run`], such as the HTTP trigger
+/// served by the shim.
+pub(crate) async fn build<T>(
+    trigger: T,
+    app: App,
+    loader: &ComponentLoader,
+    config: &AppConfig,
+    args: FactorsArgs,
+) -> anyhow::Result<(T, TriggerApp<T, TriggerFactors>)>
+where
+    T: Trigger<TriggerFactors>,
+{
+    let mut builder: TriggerAppBuilder<_, FactorsBuilder> = TriggerAppBuilder::new(trigger);
+    let trigger_app = builder
+        .build(app, factors_config(config), args, loader)
+        .await?;
+    Ok((builder.trigger, trigger_app))
+}
+
+/// Maps the result of the command trigger to the exit code of the container.
+///
+/// The code the guest passed to `wasi:cli/exit` becomes the exit code, and
+/// traps map to [`SPIN_COMMAND_TRAP_EXIT_CODE`]. Any other error is returned.
+#[cfg(feature = "command")]
+pub(crate) fn command_exit_code(result: anyhow::Result<()>) -> anyhow::Result<i32> {
+    let Err(err) = result else {
+        return Ok(0);
+    };
+    if let Some(I32Exit(code)) = err.chain().find_map(|e| e.downcast_ref::<I32Exit>()) {
+        info!(" >>> command exited with code {code}");
+        return Ok(*code);
+    }
+    if let Some(trap) = err.chain().find_map(|e| e.downcast_ref::<wasmtime::Trap>()) {
+        log::error!(" >>> command trapped ({trap}): {err:?}");
+        return Ok(SPIN_COMMAND_TRAP_EXIT_CODE);
+    }
+    Err(err)
+}
 
-    let future = builder
-        .run(app, factors_config(), Default::default(), loader)
+/// Interfaces through which components handle messages, newest first. Message
+/// triggers other than Redis reuse the Redis interface, so that components
+/// written for the `RedisTrigger` can consume messages from other brokers.
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+const INBOUND_MESSAGE_INTERFACES: [&str; 2] = [
+    "fermyon:spin/inbound-redis@2.0.0",
+    "fermyon:spin/inbound-redis",
+];
+
+/// Delays before a message a component failed to handle is redelivered
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+pub(crate) const REDELIVERY_BACKOFF: Backoff = Backoff::new(
+    constants::SPIN_MESSAGE_REDELIVERY_BACKOFF_INITIAL,
+    constants::SPIN_MESSAGE_REDELIVERY_BACKOFF_MAX,
+);
+
+/// Delivers a message to the `handle-message` export of the component.
+///
+/// The message triggers implemented by the shim (Kafka, NATS and AMQP) share
+/// how they are configured and deliver messages. The connection to the broker
+/// is configured in the `[trigger.<type>]` table of the runtime config, which
+/// overrides the `[application.trigger.<type>]` table of the manifest, and
+/// components are bound to the topics, subjects or queues they consume in
+/// `[[trigger.<type>]]` tables of the manifest. Each message is handled by a
+/// new instance of the component, through the interfaces Redis triggers use.
+///
+/// Returns an error if the component could not be instantiated or failed to
+/// handle the message.
+#[cfg(any(feature = "kafka", feature = "nats", feature = "amqp"))]
+pub(crate) async fn handle_message<T, F>(
+    trigger_app: &TriggerApp<T, F>,
+    component_id: &str,
+    payload: &[u8],
+) -> anyhow::Result<()>
+where
+    T: Trigger<F, InstanceState = ()>,
+    F: RuntimeFactors,
+{
+    let (instance, mut store) = trigger_app.prepare(component_id)?.instantiate(()).await?;
+    let func = INBOUND_MESSAGE_INTERFACES
+        .iter()
+        .find_map(|interface| {
+            let interface = instance.get_export(&mut store, None, interface)?;
+            let func = instance.get_export(&mut store, Some(&interface), "handle-message")?;
+            instance.get_func(&mut store, &func)
+        })
+        .with_context(|| {
+            format!("component {component_id:?} does not export the inbound-redis interface")
+        })?;
+    let message = Val::List(payload.iter().copied().map(Val::U8).collect());
+    let mut results = [Val::Bool(false)];
+    func.call_async(&mut store, &[message], &mut results)
         .await?;
-    Ok(Box::pin(future))
+    func.post_return_async(&mut store).await?;
+    match &results[0] {
+        Val::Result(Ok(_)) => Ok(()),
+        Val::Result(Err(e)) => anyhow::bail!("component returned an error: {e:?}"),
+        other => anyhow::bail!("unexpected result of handle-message: {other:?}"),
+    }
+}
+
+/// Creates the [`ComponentLoader`] for an application loaded from the given [`Source`].
+pub(crate) fn component_loader(app_source: &Source) -> ComponentLoader {
+    let mut loader = ComponentLoader::default();
+    match app_source {
+        Source::Oci(_) => unsafe {
+            // Configure the loader to support loading AOT compiled components..
+            // Since all precompiled components were either sealed by the shim (during `precompile`)
+            // or explicitly trusted by the node (see `verify_wasm_layer`), this operation can be
+            // considered safe.
+            loader.enable_loading_aot_compiled_components();
+        },
+        // Currently, it is only possible to precompile applications distributed using
+        // `spin registry push`
+        Source::File(_) => {}
+    };
+    loader
 }
 
-/// Configuration for the factors.
-fn factors_config() -> FactorsConfig {
-    // Load in runtime config if one exists at expected location
-    let runtime_config_file = Path::new(RUNTIME_CONFIG_PATH)
-        .exists()
-        .then(|| RUNTIME_CONFIG_PATH.into());
-    // Configure the application state directory path. This is used in the default
-    // locations for logs, key value stores, etc.
-    let state_dir = PathBuf::from(SPIN_TRIGGER_WORKING_DIR).join(SPIN_DEFAULT_STATE_DIR);
+/// Configuration for the factors. The state directory is used in the default
+/// locations for key value stores, SQLite databases, etc.
+fn factors_config(config: &AppConfig) -> FactorsConfig {
     FactorsConfig {
         working_dir: SPIN_TRIGGER_WORKING_DIR.into(),
-        runtime_config_file,
-        state_dir: UserProvidedPath::Provided(state_dir),
+        runtime_config_file: config.runtime_config_file.clone(),
+        state_dir: UserProvidedPath::Provided(config.state_dir.clone()),
         // Explicitly do not set log dir in order to force logs to be displayed to stdout.
         // Otherwise, would default to the state directory.
         log_dir: UserProvidedPath::Unset,
@@ -66,39 +241,69 @@ fn factors_config() -> FactorsConfig {
     }
 }
 
-/// get the supported trigger types from the `LockedApp`.
-///
-/// this function filters the trigger types to only return the ones that are currently supported.
-/// If an unsupported trigger type is found, it returns an error indicating which trigger type is unsupported.
-///
-/// supported trigger types include:
-/// - redis
-/// - http
-/// - sqs
-/// - mqtt
-/// - command
-///
-/// Note: this function returns a `HashSet` of supported trigger types. Duplicates are removed.
-pub(crate) fn get_supported_triggers(locked_app: &LockedApp) -> anyhow::Result<HashSet<String>> {
-    let supported_triggers: HashSet<&str> = HashSet::from([
-        HTTP_TRIGGER_TYPE,
-        REDIS_TRIGGER_TYPE,
-        SQS_TRIGGER_TYPE,
-        COMMAND_TRIGGER_TYPE,
-        MQTT_TRIGGER_TYPE,
-    ]);
-
-    locked_app.triggers.iter()
-        .map(|trigger| {
-            let trigger_type = &trigger.trigger_type;
-            if !supported_triggers.contains(trigger_type.as_str()) {
-                Err(anyhow::anyhow!(
-                    "Only Http, Redis, MQTT, SQS, and Command triggers are currently supported. Found unsupported trigger: {:?}",
-                    trigger_type
-                ))
-            } else {
-                Ok(trigger_type.clone())
-            }
-        })
-        .collect::<anyhow::Result<HashSet<_>>>()
+/// Runs a trigger loop, whose handler sends what it is called with to the given
+/// sender, until the handler was called `count` times, and returns what it was
+/// called with. Panics if the loop ends before.
+#[cfg(all(
+    test,
+    any(
+        feature = "cron",
+        feature = "kafka",
+        feature = "nats",
+        feature = "amqp"
+    )
+))]
+pub(crate) async fn collect_calls<T, R, Fut>(
+    count: usize,
+    run: impl FnOnce(tokio::sync::mpsc::UnboundedSender<T>) -> Fut,
+) -> Vec<T>
+where
+    Fut: Future<Output = R>,
+    R: std::fmt::Debug,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let run = run(tx);
+    let collect = async move {
+        let mut calls = Vec::with_capacity(count);
+        while calls.len() < count {
+            calls.push(rx.recv().await.expect("handler was dropped"));
+        }
+        calls
+    };
+    match futures::future::select(std::pin::pin!(run), std::pin::pin!(collect)).await {
+        futures::future::Either::Right((calls, _)) => calls,
+        futures::future::Either::Left((result, _)) => panic!("trigger loop ended: {result:?}"),
+    }
+}
+
+#[cfg(all(test, feature = "command"))]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn command_exit_code_from_guest_exit() {
+        assert_eq!(command_exit_code(Ok(())).unwrap(), 0);
+        let exit = Err(anyhow::Error::new(I32Exit(3))).context("failed to run command");
+        assert_eq!(command_exit_code(exit).unwrap(), 3);
+        let exit = Err(anyhow::Error::new(I32Exit(0)));
+        assert_eq!(command_exit_code(exit).unwrap(), 0);
+    }
+
+    #[test]
+    fn command_exit_code_from_trap() {
+        let trap = Err(anyhow::Error::new(wasmtime::Trap::UnreachableCodeReached))
+            .context("failed to run command");
+        assert_eq!(
+            command_exit_code(trap).unwrap(),
+            SPIN_COMMAND_TRAP_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn command_exit_code_from_host_error() {
+        let err = Err(anyhow::anyhow!("failed to instantiate component"));
+        assert!(command_exit_code(err).is_err());
+    }
 }
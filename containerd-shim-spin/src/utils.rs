@@ -1,46 +1,82 @@
-use std::{
-    env,
-    net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf,
-};
+#[cfg(feature = "http")]
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::{collections::HashMap, env, io::Read, path::Path, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "http")]
+use anyhow::anyhow;
+use anyhow::{Context, Result};
 use containerd_shim_wasm::sandbox::WasmLayer;
 use oci_spec::image::MediaType;
-use spin_app::locked::LockedApp;
+use spin_common::sha256::hex_digest_from_bytes;
 use spin_loader::cache::Cache;
 
-use crate::constants;
+use crate::{constants, source::ImageReference};
 
-// create a cache directory at /.cache
+/// Standard OpenTelemetry environment variable for resource attributes
+pub(crate) const OTEL_RESOURCE_ATTRIBUTES_ENV: &str = "OTEL_RESOURCE_ATTRIBUTES";
+
+// create the cache directory of the app
 // this is needed for the spin LocalLoader to work
 // TODO: spin should provide a more flexible `loader::from_file` that
 // does not assume the existence of a cache directory
-pub(crate) async fn initialize_cache() -> Result<Cache, anyhow::Error> {
-    let cache_dir = PathBuf::from("/.cache");
-    let cache = Cache::new(Some(cache_dir.clone()))
+pub(crate) async fn initialize_cache(cache_dir: &Path) -> Result<Cache, anyhow::Error> {
+    Cache::new(Some(cache_dir.to_path_buf()))
         .await
-        .context("failed to create cache")?;
-    env::set_var("XDG_CACHE_HOME", &cache_dir);
-    Ok(cache)
+        .context("failed to create cache")
 }
 
+// Writes an archive layer to the cache, along with each file it contains by
+// digest. Unlike spin_oci::client::unpack_archive_layer, the files are read
+// from the archive directly rather than unpacked to a temporary directory,
+// which depends on TMPDIR since /tmp is either not found or not accessible in
+// the shim environment.
 pub(crate) async fn handle_archive_layer(
     cache: &Cache,
     bytes: impl AsRef<[u8]>,
     digest: impl AsRef<str>,
 ) -> Result<()> {
-    // spin_oci::client::unpack_archive_layer attempts to create a tempdir via tempfile::tempdir()
-    // which will fall back to /tmp if TMPDIR is not set. /tmp is either not found or not accessible
-    // in the shim environment, hence setting to current working directory.
-    if env::var("TMPDIR").is_err() {
-        log::debug!(
-            "<<< TMPDIR is not set; setting to current working directory for unpacking archive layer"
-        );
-        env::set_var("TMPDIR", env::current_dir().unwrap_or(".".into()));
+    cache.write_data(&bytes, &digest).await?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_ref()));
+    // Digests of the files read so far by path, as hard links name the file
+    // holding their content
+    let mut digests = HashMap::new();
+    for entry in archive.entries().context("failed to read archive layer")? {
+        let mut entry = entry.context("failed to read archive layer entry")?;
+        let path = entry
+            .path()
+            .context("failed to read archive layer entry path")?
+            .into_owned();
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_hard_link() {
+            let target = entry
+                .link_name()
+                .context("failed to read archive layer entry link")?
+                .with_context(|| format!("archive layer hard link {path:?} has no target"))?
+                .into_owned();
+            // The content of the target, and so of the link, is already in the cache
+            let digest = digests.get(&target).cloned().with_context(|| {
+                format!("archive layer hard link {path:?} links to {target:?}, which is not a file preceding it")
+            })?;
+            log::debug!("<<< archive layer file {path:?} is a hard link to {digest}");
+            digests.insert(path, digest);
+            continue;
+        }
+        if !entry_type.is_file() {
+            continue;
+        }
+        let mut content = Vec::new();
+        entry
+            .read_to_end(&mut content)
+            .context("failed to read archive layer entry")?;
+        let digest = format!("sha256:{}", hex_digest_from_bytes(&content));
+        if cache.data_file(&digest).is_ok() {
+            log::debug!("<<< archive layer file {digest} already in cache");
+        } else {
+            cache.write_data(&content, &digest).await?;
+        }
+        digests.insert(path, digest);
     }
-
-    spin_oci::client::unpack_archive_layer(cache, bytes, digest).await
+    Ok(())
 }
 
 // Returns Some(WasmLayer) if the layer contains wasm, otherwise None
@@ -53,6 +89,44 @@ pub(crate) fn is_wasm_content(layer: &WasmLayer) -> Option<WasmLayer> {
     None
 }
 
+// Returns true if the environment variable is set to a truthy value
+pub(crate) fn is_env_flag_set(name: &str) -> bool {
+    env::var(name)
+        .map(|val| matches!(val.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+// Returns the comma separated entries of the environment variable, or None if
+// it is not set or has no entries
+pub(crate) fn env_list(name: &str) -> Option<Vec<String>> {
+    let list = env::var(name)
+        .ok()?
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    (!list.is_empty()).then_some(list)
+}
+
+// Returns how long in-flight work may take to finish after a termination
+// signal. Defaults to zero, which aborts in-flight work immediately.
+pub(crate) fn shutdown_drain_period() -> Result<Duration> {
+    match env::var(constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV) {
+        Ok(secs) => secs
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .with_context(|| {
+                format!(
+                    "invalid {}: {secs:?} is not a number of seconds",
+                    constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV
+                )
+            }),
+        Err(_) => Ok(Duration::ZERO),
+    }
+}
+
+#[cfg(feature = "http")]
 pub(crate) fn parse_addr(addr: &str) -> Result<SocketAddr> {
     let addrs: SocketAddr = addr
         .to_socket_addrs()?
@@ -61,71 +135,131 @@ pub(crate) fn parse_addr(addr: &str) -> Result<SocketAddr> {
     Ok(addrs)
 }
 
-// For each Spin app variable, checks if a container environment variable with
-// the same name exists and duplicates it in the environment with the
-// application variable prefix
-pub(crate) fn configure_application_variables_from_environment_variables(
-    resolved: &LockedApp,
-) -> Result<()> {
-    resolved
-        .variables
-        .keys()
-        .map(|k| k.as_ref())
-        .map(str::to_ascii_uppercase)
-        .for_each(|variable| {
-            env::var(&variable)
-                .map(|val| {
-                    let prefixed = format!(
-                        "{}_{}",
-                        constants::SPIN_APPLICATION_VARIABLE_PREFIX,
-                        variable
-                    );
-                    env::set_var(prefixed, val);
-                })
-                .ok();
-        });
-    Ok(())
+/// Returns the image attributes followed by the existing attributes, in the
+/// format of `OTEL_RESOURCE_ATTRIBUTES`, leaving out the image attributes
+/// whose keys are already set
+pub(crate) fn telemetry_resource_attributes(
+    image: &ImageReference,
+    existing: Option<&str>,
+) -> String {
+    let existing = existing.unwrap_or_default();
+    let existing_keys: Vec<&str> = existing
+        .split_terminator(',')
+        .filter_map(|attribute| attribute.split_once('='))
+        .map(|(key, _)| key.trim())
+        .collect();
+    image
+        .resource_attributes()
+        .into_iter()
+        .filter(|(key, _)| !existing_keys.contains(key))
+        .map(|(key, value)| format!("{key}={value}"))
+        .chain((!existing.is_empty()).then(|| existing.to_string()))
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
-
     use super::*;
 
+    #[tokio::test]
+    async fn test_handle_archive_layer() {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, content) in [("static/index.html", "<html>"), ("data.json", "{}")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "static/index.htm", "static/index.html")
+            .unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = initialize_cache(cache_dir.path()).await.unwrap();
+
+        handle_archive_layer(&cache, &archive, "sha256:archive")
+            .await
+            .unwrap();
+        assert!(cache.data_file("sha256:archive").is_ok());
+        for content in ["<html>", "{}"] {
+            let digest = format!("sha256:{}", hex_digest_from_bytes(content));
+            assert_eq!(
+                std::fs::read(cache.data_file(&digest).unwrap()).unwrap(),
+                content.as_bytes()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_archive_layer_rejects_dangling_hard_links() {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "static/index.htm", "static/index.html")
+            .unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = initialize_cache(cache_dir.path()).await.unwrap();
+
+        let err = handle_archive_layer(&cache, &archive, "sha256:archive")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("static/index.html"), "{err}");
+    }
+
     #[test]
-    fn test_configure_application_variables_from_environment_variables() {
-        temp_env::with_vars(
-            [
-                ("SPIN_VARIABLE_DO_NOT_RESET", Some("val1")),
-                ("SHOULD_BE_PREFIXED", Some("val2")),
-                ("ignored_if_not_uppercased_env", Some("val3")),
-            ],
+    fn container_resource_attributes_take_precedence() {
+        let image = ImageReference {
+            name: "ghcr.io/spinkube/app:v1".to_string(),
+            digest: Some("sha256:1234".to_string()),
+        };
+        assert_eq!(
+            telemetry_resource_attributes(&image, Some("container.image.name=app,team=platform")),
+            "oci.manifest.digest=sha256:1234,container.image.name=app,team=platform"
+        );
+        assert_eq!(
+            telemetry_resource_attributes(&image, Some("")),
+            "container.image.name=ghcr.io/spinkube/app:v1,oci.manifest.digest=sha256:1234"
+        );
+    }
+
+    #[test]
+    fn test_shutdown_drain_period() {
+        temp_env::with_var(
+            constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV,
+            Some("15"),
+            || {
+                assert_eq!(shutdown_drain_period().unwrap(), Duration::from_secs(15));
+            },
+        );
+        temp_env::with_var(
+            constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV,
+            Some("15s"),
             || {
-                let app_json = r#"
-                {
-                    "spin_lock_version": 1,
-                    "entrypoint": "test",
-                    "components": [],
-                    "variables": {"should_be_prefixed": { "required": "true"},  "do_not_reset" : { "required": "true"}, "not_set_as_container_env": { "required": "true"}, "ignored_if_not_uppercased_env": { "required": "true"}},
-                    "triggers": []
-                }"#;
-                let locked_app = LockedApp::from_json(app_json.as_bytes()).unwrap();
-
-                configure_application_variables_from_environment_variables(&locked_app).unwrap();
-                assert_eq!(env::var("SPIN_VARIABLE_DO_NOT_RESET").unwrap(), "val1");
-                assert_eq!(
-                    env::var("SPIN_VARIABLE_SHOULD_BE_PREFIXED").unwrap(),
-                    "val2"
-                );
-                assert!(env::var("SPIN_VARIABLE_NOT_SET_AS_CONTAINER_ENV").is_err());
-                assert!(env::var("SPIN_VARIABLE_IGNORED_IF_NOT_UPPERCASED_ENV").is_err());
-                // Original env vars are still retained but not set in variable provider
-                assert!(env::var("SHOULD_BE_PREFIXED").is_ok());
+                assert!(shutdown_drain_period().is_err());
             },
         );
+        temp_env::with_var_unset(constants::SPIN_SHUTDOWN_DRAIN_PERIOD_ENV, || {
+            assert_eq!(shutdown_drain_period().unwrap(), Duration::ZERO);
+        });
     }
 
+    #[cfg(feature = "http")]
     #[test]
     fn can_parse_spin_address() {
         let parsed = parse_addr(constants::SPIN_ADDR_DEFAULT).unwrap();
@@ -133,6 +267,23 @@ mod tests {
         assert_eq!(parsed.ip().to_string(), "0.0.0.0");
     }
 
+    #[test]
+    fn is_env_flag_set_test() {
+        temp_env::with_vars(
+            [
+                ("SPIN_TEST_FLAG_TRUE", Some("True")),
+                ("SPIN_TEST_FLAG_ONE", Some("1")),
+                ("SPIN_TEST_FLAG_FALSE", Some("false")),
+            ],
+            || {
+                assert!(is_env_flag_set("SPIN_TEST_FLAG_TRUE"));
+                assert!(is_env_flag_set("SPIN_TEST_FLAG_ONE"));
+                assert!(!is_env_flag_set("SPIN_TEST_FLAG_FALSE"));
+                assert!(!is_env_flag_set("SPIN_TEST_FLAG_UNSET"));
+            },
+        );
+    }
+
     #[test]
     fn is_wasm_content_test() {
         let wasm_content = WasmLayer {
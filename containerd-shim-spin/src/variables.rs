@@ -0,0 +1,605 @@
+//! This module contains the sources of Spin application variables provided by the container
+//!
+//! A variable is provided by the container environment variable with the
+//! uppercased name of the variable, unless it is mapped to another environment
+//! variable through `SPIN_VARIABLES_MAP` or `SPIN_VARIABLES_ENV_PREFIX` sets a
+//! prefix for the names.
+//!
+//! Besides container environment variables, variables can be read from a
+//! directory holding one file per variable, named after the variable, such as a
+//! mounted Kubernetes Secret. The directory is set through the
+//! `SPIN_VARIABLES_SECRETS_DIR` environment variable of the container and takes
+//! precedence over environment variables. Files are read on each lookup, so that
+//! updated secrets apply without restarting the app.
+//!
+//! The environment of the container is read once into a [`VariablesConfig`],
+//! which is handed to the variables provider of the triggers rather than
+//! exported to the environment of the process.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use spin_app::locked::{LockedApp, LockedComponent, LockedMap, Variable};
+use spin_expressions::{Key, Provider};
+use spin_factor_variables::runtime_config::RuntimeConfig as VariablesRuntimeConfig;
+use spin_factors_executor::FactorsExecutor;
+use spin_runtime_factors::{FactorsBuilder as SpinFactorsBuilder, TriggerAppArgs, TriggerFactors};
+use spin_trigger::cli::{FactorsConfig, RuntimeFactorsBuilder};
+
+use crate::constants::{
+    SPIN_APPLICATION_VARIABLE_PREFIX, SPIN_VARIABLES_ENV_PREFIX_ENV, SPIN_VARIABLES_MAP_ENV,
+    SPIN_VARIABLES_SECRETS_DIR_ENV,
+};
+#[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+use crate::shutdown::DrainHooks;
+
+/// Where the values of Spin application variables come from
+#[derive(Clone, Default)]
+pub(crate) struct VariablesConfig {
+    /// Environment variables of the container
+    env: HashMap<String, String>,
+    env_names: EnvNames,
+    secrets: Option<SecretsDir>,
+}
+
+impl VariablesConfig {
+    /// Returns the configuration from the environment variables of the container
+    pub(crate) fn from_env() -> Result<Self> {
+        Self::new(env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+
+    /// Returns the configuration from the given environment variables
+    pub(crate) fn new(env: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let env = env.into_iter().collect::<HashMap<_, _>>();
+        let env_names = EnvNames::new(&env)?;
+        let secrets = env
+            .get(SPIN_VARIABLES_SECRETS_DIR_ENV)
+            .map(|dir| SecretsDir::new(dir.into()))
+            .transpose()?;
+        Ok(Self {
+            env,
+            env_names,
+            secrets,
+        })
+    }
+
+    /// Returns the secrets directory, if any
+    pub(crate) fn secrets_dir(&self) -> Option<&Path> {
+        self.secrets.as_ref().map(SecretsDir::dir)
+    }
+
+    /// Returns the name of the environment variable providing the variable
+    pub(crate) fn env_name(&self, variable: &str) -> String {
+        self.env_names.env_name(variable)
+    }
+
+    /// Returns the variables mapped through `SPIN_VARIABLES_MAP` that the app
+    /// does not declare, such as misspelled ones, which provide nothing.
+    pub(crate) fn undeclared_mapped_variables(&self, locked_app: &LockedApp) -> Vec<&str> {
+        let mut undeclared = self
+            .env_names
+            .map
+            .keys()
+            .filter(|name| !locked_app.variables.contains_key(name.as_str()))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        undeclared.sort();
+        undeclared
+    }
+
+    /// Returns the value of the variable provided by the container: its file in
+    /// the secrets directory or the environment variable providing it, in that
+    /// order.
+    fn provided_value(&self, name: &str) -> Result<Option<String>> {
+        if let Some(secrets) = &self.secrets {
+            if let Some(value) = secrets.get(name)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(self.env.get(&self.env_name(name)).cloned())
+    }
+
+    /// Returns the value of the variable: the value provided by the container,
+    /// the application variable provider environment variable, or the default
+    /// of the variable, in that order.
+    pub(crate) fn value(&self, name: &str, variable: &Variable) -> Option<String> {
+        let provided = self.provided_value(name).unwrap_or_else(|e| {
+            log::warn!("failed to read variable {name:?}: {e:?}");
+            None
+        });
+        provided
+            .or_else(|| self.env.get(&spin_env_name(name)).cloned())
+            .or_else(|| variable.default.clone())
+    }
+
+    /// Returns the application variable provider environment variables that
+    /// hand the values provided by container environment variables to processes
+    /// other than the shim, such as trigger plugins. Variables provided by the
+    /// secrets directory are left out, as the environment of a process can be
+    /// read by others.
+    pub(crate) fn provider_env(&self, variables: &LockedMap<Variable>) -> Vec<(String, String)> {
+        variables
+            .keys()
+            .filter(|name| !self.is_secret(name))
+            .filter_map(|name| {
+                let value = self.env.get(&self.env_name(name))?;
+                Some((spin_env_name(name), value.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns whether the variable is provided by the secrets directory,
+    /// including by a file that cannot be read
+    fn is_secret(&self, name: &str) -> bool {
+        self.secrets
+            .as_ref()
+            .is_some_and(|secrets| !matches!(secrets.get(name), Ok(None)))
+    }
+}
+
+// The values of the environment variables may be secrets
+impl fmt::Debug for VariablesConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VariablesConfig")
+            .field("env_names", &self.env_names)
+            .field("secrets", &self.secrets)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the name of the application variable provider environment variable of the variable
+fn spin_env_name(name: &str) -> String {
+    format!(
+        "{}_{}",
+        SPIN_APPLICATION_VARIABLE_PREFIX,
+        name.to_ascii_uppercase()
+    )
+}
+
+/// Names of the container environment variables providing Spin application variables
+#[derive(Clone, Debug, Default, PartialEq)]
+struct EnvNames {
+    /// Environment variable names by variable, set through `SPIN_VARIABLES_MAP`
+    map: HashMap<String, String>,
+    /// Prefix of the names of unmapped variables, set through `SPIN_VARIABLES_ENV_PREFIX`
+    prefix: String,
+}
+
+impl EnvNames {
+    /// Returns the names configured through the given environment variables
+    fn new(env: &HashMap<String, String>) -> Result<Self> {
+        let map = env
+            .get(SPIN_VARIABLES_MAP_ENV)
+            .map(String::as_str)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(parse_mapping)
+            .collect::<Result<_>>()?;
+        let prefix = env
+            .get(SPIN_VARIABLES_ENV_PREFIX_ENV)
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self { map, prefix })
+    }
+
+    /// Returns the name of the environment variable providing the variable
+    fn env_name(&self, variable: &str) -> String {
+        match self.map.get(variable) {
+            Some(name) => name.clone(),
+            None => format!("{}{}", self.prefix, variable.to_ascii_uppercase()),
+        }
+    }
+}
+
+/// Parses a `variable=ENV_NAME` entry of `SPIN_VARIABLES_MAP`
+fn parse_mapping(entry: &str) -> Result<(String, String)> {
+    match entry.split_once('=') {
+        Some((variable, name)) if !variable.trim().is_empty() && !name.trim().is_empty() => {
+            Ok((variable.trim().to_string(), name.trim().to_string()))
+        }
+        _ => bail!("invalid {SPIN_VARIABLES_MAP_ENV} entry {entry:?}: expected variable=ENV_NAME"),
+    }
+}
+
+/// A directory holding one file per Spin application variable
+#[derive(Clone, Debug)]
+struct SecretsDir {
+    dir: PathBuf,
+}
+
+impl SecretsDir {
+    fn new(dir: PathBuf) -> Result<Self> {
+        if !dir.is_dir() {
+            bail!("invalid {SPIN_VARIABLES_SECRETS_DIR_ENV}: {dir:?} is not a directory");
+        }
+        Ok(Self { dir })
+    }
+
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the value of the variable from the file named after the
+    /// variable, or after the uppercased variable like the environment
+    /// variable, without trailing line breaks.
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        for file_name in [name.to_string(), name.to_ascii_uppercase()] {
+            let path = self.dir.join(file_name);
+            match fs::read_to_string(&path) {
+                Ok(value) => return Ok(Some(value.trim_end_matches(['\r', '\n']).to_string())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("failed to read variable {name:?} from {path:?}"))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Checks that all required variables of the app have a value, returning a
+/// single error that lists each missing variable along with the components
+/// using it and the container environment variable that would provide it.
+///
+/// If the runtime config configures variables providers, such as Vault, the
+/// missing variables may be provided by them, so they are only logged as a
+/// warning.
+pub(crate) fn check_required_variables(
+    locked_app: &LockedApp,
+    variables: &VariablesConfig,
+    runtime_config_providers: bool,
+) -> Result<()> {
+    let references = locked_app
+        .components
+        .iter()
+        .map(|c| (c.id.as_str(), component_variable_references(c)))
+        .collect::<Vec<_>>();
+    let missing = locked_app
+        .variables
+        .iter()
+        .filter(|(name, variable)| {
+            variable.default.is_none() && variables.value(name, variable).is_none()
+        })
+        .map(|(name, _)| {
+            let components = references
+                .iter()
+                .filter(|(_, references)| references.contains(name))
+                .map(|(id, _)| format!("{id:?}"))
+                .collect::<Vec<_>>();
+            let used_by = match components.as_slice() {
+                [] => "not used by any component".to_string(),
+                components => format!("used by {}", components.join(", ")),
+            };
+            let mut fix = format!("set the environment variable {}", variables.env_name(name));
+            if let Some(secrets_dir) = variables.secrets_dir() {
+                fix.push_str(&format!(" or the file {:?}", secrets_dir.join(name)));
+            }
+            format!("\n  {name:?} ({used_by}): {fix}")
+        })
+        .collect::<String>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if runtime_config_providers {
+        log::warn!(
+            " >>> Required application variables are not provided by the container and must be provided by the runtime config:{missing}"
+        );
+        return Ok(());
+    }
+    bail!("Required application variables have no value:{missing}");
+}
+
+/// Returns the names of the variables referenced by the config, allowed
+/// outbound hosts and other metadata of the component
+pub(crate) fn component_variable_references(component: &LockedComponent) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for value in component.config.values() {
+        collect_template_references(value, &mut names);
+    }
+    for value in component.metadata.values() {
+        collect_value_template_references(value, &mut names);
+    }
+    names
+}
+
+/// Adds the names of the variables referenced by the strings in the value
+pub(crate) fn collect_value_template_references(
+    value: &serde_json::Value,
+    names: &mut HashSet<String>,
+) {
+    match value {
+        serde_json::Value::String(s) => collect_template_references(s, names),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .for_each(|v| collect_value_template_references(v, names)),
+        serde_json::Value::Object(values) => values
+            .values()
+            .for_each(|v| collect_value_template_references(v, names)),
+        _ => {}
+    }
+}
+
+/// Adds the names of the `{{ variable }}` references of the template
+fn collect_template_references(template: &str, names: &mut HashSet<String>) {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        names.insert(rest[start + 2..start + len].trim().to_string());
+        rest = &rest[start + len + 2..];
+    }
+}
+
+/// Provides the Spin application variables provided by the container
+#[derive(Debug)]
+struct ContainerProvider(VariablesConfig);
+
+#[async_trait]
+impl Provider for ContainerProvider {
+    async fn get(&self, key: &Key) -> Result<Option<String>> {
+        self.0.provided_value(key.as_str())
+    }
+}
+
+/// Arguments the factors of the triggers are built with
+#[derive(Default, clap::Args)]
+pub(crate) struct FactorsArgs {
+    #[clap(flatten)]
+    spin: TriggerAppArgs,
+    #[clap(skip)]
+    variables: VariablesConfig,
+    #[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+    #[clap(skip)]
+    drain: Option<DrainHooks>,
+}
+
+impl FactorsArgs {
+    pub(crate) fn new(variables: VariablesConfig) -> Self {
+        Self {
+            variables,
+            ..Default::default()
+        }
+    }
+
+    /// Drains the instances of components through the given hooks on shutdown
+    #[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+    pub(crate) fn with_drain(self, drain: DrainHooks) -> Self {
+        Self {
+            drain: Some(drain),
+            ..self
+        }
+    }
+}
+
+/// Builds the factors of the triggers like Spin does, with the variables
+/// provided by the container taking precedence over the configured providers.
+pub(crate) struct FactorsBuilder;
+
+impl RuntimeFactorsBuilder for FactorsBuilder {
+    type CliArgs = FactorsArgs;
+    type Factors = TriggerFactors;
+    type RuntimeConfig = <SpinFactorsBuilder as RuntimeFactorsBuilder>::RuntimeConfig;
+
+    fn build(
+        config: &FactorsConfig,
+        args: &Self::CliArgs,
+    ) -> Result<(Self::Factors, Self::RuntimeConfig)> {
+        let (factors, mut runtime_config) = SpinFactorsBuilder::build(config, &args.spin)?;
+        let provider: Box<dyn Provider> = Box::new(ContainerProvider(args.variables.clone()));
+        match &mut runtime_config.runtime_config.variables {
+            Some(variables) => variables.providers.insert(0, provider),
+            None => {
+                runtime_config.runtime_config.variables = Some(VariablesRuntimeConfig {
+                    providers: vec![provider],
+                })
+            }
+        }
+        Ok((factors, runtime_config))
+    }
+
+    fn configure_app<U: Send + 'static>(
+        executor: &mut FactorsExecutor<Self::Factors, U>,
+        runtime_config: &Self::RuntimeConfig,
+        args: &Self::CliArgs,
+    ) -> Result<()> {
+        SpinFactorsBuilder::configure_app(executor, runtime_config, &args.spin)?;
+        #[cfg(any(feature = "redis", feature = "mqtt", feature = "sqs"))]
+        if let Some(drain) = &args.drain {
+            executor.add_hooks(drain.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables_config(env: &[(&str, &str)]) -> Result<VariablesConfig> {
+        VariablesConfig::new(
+            env.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    fn variable(default: Option<&str>) -> Variable {
+        serde_json::from_value(serde_json::json!({ "default": default })).unwrap()
+    }
+
+    #[test]
+    fn secrets_dir_reads_variable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("db_url"), "postgres://db\n").unwrap();
+        fs::write(dir.path().join("API_TOKEN"), "secret").unwrap();
+        let secrets = SecretsDir::new(dir.path().to_path_buf()).unwrap();
+
+        assert_eq!(
+            secrets.get("db_url").unwrap().as_deref(),
+            Some("postgres://db")
+        );
+        assert_eq!(secrets.get("api_token").unwrap().as_deref(), Some("secret"));
+        assert_eq!(secrets.get("missing").unwrap(), None);
+
+        // Updated files are read on the next lookup
+        fs::write(dir.path().join("db_url"), "postgres://replica\r\n").unwrap();
+        assert_eq!(
+            secrets.get("db_url").unwrap().as_deref(),
+            Some("postgres://replica")
+        );
+    }
+
+    #[test]
+    fn secrets_dir_must_be_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(
+            variables_config(&[(SPIN_VARIABLES_SECRETS_DIR_ENV, missing.to_str().unwrap())])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn env_names_from_map_and_prefix() {
+        let config = variables_config(&[
+            (
+                SPIN_VARIABLES_MAP_ENV,
+                "db_url=DATABASE_URL, api_token = TOKEN",
+            ),
+            (SPIN_VARIABLES_ENV_PREFIX_ENV, "APP_"),
+        ])
+        .unwrap();
+        assert_eq!(config.env_name("db_url"), "DATABASE_URL");
+        assert_eq!(config.env_name("api_token"), "TOKEN");
+        assert_eq!(config.env_name("region"), "APP_REGION");
+
+        let config = variables_config(&[]).unwrap();
+        assert_eq!(config.env_names, EnvNames::default());
+        assert_eq!(config.env_name("db_url"), "DB_URL");
+
+        assert!(variables_config(&[(SPIN_VARIABLES_MAP_ENV, "db_url")]).is_err());
+    }
+
+    #[test]
+    fn variable_values_in_order_of_precedence() {
+        let secrets = tempfile::tempdir().unwrap();
+        fs::write(secrets.path().join("db_url"), "from-secret\n").unwrap();
+        let config = variables_config(&[
+            (
+                SPIN_VARIABLES_SECRETS_DIR_ENV,
+                secrets.path().to_str().unwrap(),
+            ),
+            (SPIN_VARIABLES_MAP_ENV, "region=CLUSTER_REGION"),
+            ("DB_URL", "from-env"),
+            ("CLUSTER_REGION", "from-mapped-env"),
+            ("REGION", "ignored"),
+            ("SPIN_VARIABLE_API_TOKEN", "from-spin-env"),
+            ("ignored_if_not_uppercased", "ignored"),
+        ])
+        .unwrap();
+        let value = |name| config.value(name, &variable(Some("default")));
+        assert_eq!(value("db_url").as_deref(), Some("from-secret"));
+        assert_eq!(value("region").as_deref(), Some("from-mapped-env"));
+        assert_eq!(value("api_token").as_deref(), Some("from-spin-env"));
+        assert_eq!(
+            value("ignored_if_not_uppercased").as_deref(),
+            Some("default")
+        );
+        assert_eq!(config.value("missing", &variable(None)), None);
+
+        let variables = ["db_url", "region", "api_token"]
+            .into_iter()
+            .map(|name| (name.to_string(), variable(None)))
+            .collect();
+        // Secrets are not exported, even if an environment variable provides
+        // the variable as well
+        assert_eq!(
+            config.provider_env(&variables),
+            [(
+                "SPIN_VARIABLE_REGION".to_string(),
+                "from-mapped-env".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn undeclared_mapped_variables_are_reported() {
+        let app_json = serde_json::json!({
+            "spin_lock_version": 1,
+            "components": [],
+            "variables": {"db_url": {}},
+            "triggers": []
+        });
+        let locked_app = LockedApp::from_json(&serde_json::to_vec(&app_json).unwrap()).unwrap();
+        let config = variables_config(&[(
+            SPIN_VARIABLES_MAP_ENV,
+            "db_url=DATABASE_URL,dburl=DATABASE_URL,api_token=TOKEN",
+        )])
+        .unwrap();
+        assert_eq!(
+            config.undeclared_mapped_variables(&locked_app),
+            ["api_token", "dburl"]
+        );
+    }
+
+    #[test]
+    fn check_required_variables_reports_missing_variables() {
+        let app_json = serde_json::json!({
+            "spin_lock_version": 1,
+            "components": [
+                {
+                    "id": "web",
+                    "source": {"content_type": "application/wasm"},
+                    "config": {"url": "{{ db_url }}", "token": "Bearer {{api_token}}"}
+                },
+                {
+                    "id": "worker",
+                    "metadata": {"allowed_outbound_hosts": ["https://{{ db_url }}"]},
+                    "source": {"content_type": "application/wasm"}
+                }
+            ],
+            "variables": {
+                "api_token": {"secret": true},
+                "db_url": {},
+                "region": {"default": "eu"},
+                "unused": {}
+            },
+            "triggers": []
+        });
+        let locked_app = LockedApp::from_json(&serde_json::to_vec(&app_json).unwrap()).unwrap();
+
+        let config = variables_config(&[
+            (SPIN_VARIABLES_MAP_ENV, "db_url=DATABASE_URL"),
+            ("API_TOKEN", "secret"),
+        ])
+        .unwrap();
+        let err = check_required_variables(&locked_app, &config, false)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Required application variables have no value:\n  \
+             \"db_url\" (used by \"web\", \"worker\"): set the environment variable DATABASE_URL\n  \
+             \"unused\" (not used by any component): set the environment variable UNUSED"
+        );
+        // The variables may be provided by the variables providers of the runtime config
+        check_required_variables(&locked_app, &config, true).unwrap();
+
+        let config = variables_config(&[
+            ("API_TOKEN", "secret"),
+            ("DB_URL", "postgres://db"),
+            ("UNUSED", "unused"),
+        ])
+        .unwrap();
+        check_required_variables(&locked_app, &config, false).unwrap();
+    }
+}